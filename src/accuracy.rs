@@ -0,0 +1,827 @@
+use std::{collections::HashMap, convert::TryFrom, str::FromStr};
+
+use chrono::{DateTime, Local};
+use rand::Rng;
+
+use crate::{
+    error::{Result, RowRef, WksimError},
+    model::{
+        Assignment, IncorrectAnswers, Review, Srs, SrsID, Stage, Subject, SubjectID, SubjectKind,
+        MAX_LEVEL, NUM_STAGES, NUM_SUBJECT_KINDS, SUBJECT_KINDS,
+    },
+};
+
+/// How much each review counts when estimating accuracy, depending on its age.
+#[derive(Clone, Debug)]
+pub struct RecencyWeighting {
+    /// Time from which the age of reviews is measured
+    pub now: DateTime<Local>,
+    /// Reviews older than this many days are ignored
+    pub window_days: Option<f64>,
+    /// Age in days at which a review counts half as much as a current one
+    pub half_life_days: Option<f64>,
+}
+
+impl RecencyWeighting {
+    /// Returns the weight of a review done at `time`, or None if it should be
+    /// ignored.
+    fn weight(&self, time: DateTime<Local>) -> Option<f64> {
+        let age_days = self.now.signed_duration_since(time).num_seconds().max(0) as f64 / 86400.0;
+
+        match self.window_days {
+            Some(window_days) if age_days > window_days => None,
+            _ => Some(
+                self.half_life_days
+                    .map_or(1.0, |half_life| 0.5f64.powf(age_days / half_life)),
+            ),
+        }
+    }
+}
+
+/// Dirichlet prior that the accuracy at each stage is smoothed towards, so that
+/// stages with only a few reviews don't give overconfident predictions.
+#[derive(Clone, Debug)]
+pub struct AccuracyPrior {
+    /// Number of reviews that the prior is worth
+    pub strength: f64,
+    /// Use the accuracy of a typical user as the prior, instead of the user's
+    /// own accuracy over all stages
+    pub population: bool,
+}
+
+/// Reviews to which an accuracy override applies.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccuracyScope {
+    All,
+    Kind(SubjectKind),
+    Stage(Stage),
+}
+
+impl AccuracyScope {
+    fn contains(self, kind: SubjectKind, stage: Stage) -> bool {
+        match self {
+            Self::All => true,
+            Self::Kind(scope_kind) => scope_kind == kind,
+            Self::Stage(scope_stage) => scope_stage == stage,
+        }
+    }
+}
+
+impl FromStr for AccuracyScope {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        if s == "all" {
+            return Ok(Self::All);
+        }
+        if let Ok(kind) = SubjectKind::try_from(s) {
+            return Ok(Self::Kind(kind));
+        }
+
+        let stage = match s {
+            "apprentice1" => Stage::Apprentice1,
+            "apprentice2" => Stage::Apprentice2,
+            "apprentice3" => Stage::Apprentice3,
+            "apprentice4" => Stage::Apprentice4,
+            "guru1" => Stage::Guru1,
+            "guru2" => Stage::Guru2,
+            "master" => Stage::Master,
+            "enlightened" => Stage::Enlightened,
+            _ => return Err(format!("unknown subject type or SRS stage {:?}", s)),
+        };
+        Ok(Self::Stage(stage))
+    }
+}
+
+/// Hypothetical fraction of reviews without any incorrect answers, replacing
+/// the one estimated from the user's reviews.
+#[derive(Clone, Copy, Debug)]
+pub struct AccuracyOverride {
+    pub scope: AccuracyScope,
+    pub accuracy: f64,
+}
+
+impl FromStr for AccuracyOverride {
+    type Err = String;
+
+    /// Parses "<scope>=<accuracy>", or just "<accuracy>" for all reviews.
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        let (scope, accuracy) = match s.split_once('=') {
+            Some((scope, accuracy)) => (scope.parse()?, accuracy),
+            None => (AccuracyScope::All, s),
+        };
+
+        match accuracy.parse::<f64>() {
+            Ok(accuracy) if (0.0..=1.0).contains(&accuracy) => Ok(Self { scope, accuracy }),
+            _ => Err(format!("{:?} is not an accuracy between 0 and 1", accuracy)),
+        }
+    }
+}
+
+/// Ratios of the time since the previous review to the SRS interval that
+/// separate the lateness classes: on time, a bit late, late and very late.
+const LATENESS_THRESHOLDS: [f64; 3] = [1.5, 3.0, 6.0];
+const NUM_LATENESS_CLASSES: usize = 4;
+
+/// Number of expected incorrect reviews that the effect of lateness is
+/// smoothed with.
+const LATENESS_PRIOR_MISSES: f64 = 5.0;
+
+/// Rough probabilities of a typical user getting a meaning or reading wrong 0,
+/// 1 or 2 times during a review.
+const POPULATION_MEANING_PROBS: [f64; 3] = [0.9, 0.08, 0.02];
+const POPULATION_READING_PROBS: [f64; 3] = [0.85, 0.12, 0.03];
+
+/// Number of expected incorrect reviews that a subject's difficulty is
+/// smoothed with, so that a single bad review doesn't make it a leech.
+const DIFFICULTY_PRIOR_MISSES: f64 = 1.0;
+
+/// Number of reviewed subjects of the closest levels whose difficulties are
+/// drawn from, for subjects without any reviews yet.
+const DIFFICULTY_POOL_SIZE: usize = 50;
+
+/// One of the two questions asked during a review.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Question {
+    Meaning,
+    Reading,
+}
+
+/// How many times a question was answered incorrectly during reviews, with how
+/// much weight each number was given.
+#[derive(Clone, Debug, Default)]
+struct IncorrectCountDistribution {
+    total: f64,
+    /// Indexed by the number of incorrect answers
+    weights: Vec<f64>,
+}
+
+impl IncorrectCountDistribution {
+    fn from_probs(probs: &[f64]) -> Self {
+        Self {
+            total: probs.iter().sum(),
+            weights: probs.to_vec(),
+        }
+    }
+
+    /// Returns the distribution of a question that is always answered
+    /// correctly, such as the reading of a radical.
+    fn never_wrong() -> Self {
+        Self::from_probs(&[1.0])
+    }
+
+    fn add(&mut self, incorrect_count: u32, weight: f64) {
+        let index = incorrect_count as usize;
+        if self.weights.len() <= index {
+            self.weights.resize(index + 1, 0.0);
+        }
+        self.weights[index] += weight;
+        self.total += weight;
+    }
+
+    /// Adds `strength` reviews' worth of weight, distributed like `prior`.
+    fn add_prior(&mut self, prior: &Self, strength: f64) {
+        if prior.is_empty() {
+            return;
+        }
+
+        for (incorrect_count, weight) in prior.weights.iter().enumerate() {
+            self.add(incorrect_count as u32, weight / prior.total * strength);
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.total <= 0.0
+    }
+
+    /// Returns the probability of answering incorrectly at least once.
+    fn miss_probability(&self) -> f64 {
+        let correct_weight = self.weights.first().copied().unwrap_or(0.0);
+        (1.0 - correct_weight / self.total).max(0.0)
+    }
+
+    /// Changes the probability of answering correctly the first time by
+    /// `change`.
+    fn adjust_accuracy(&mut self, change: f64) {
+        self.set_accuracy(1.0 - self.miss_probability() + change);
+    }
+
+    /// Sets the probability of answering correctly the first time. The
+    /// numbers of incorrect answers keep their relative probabilities.
+    fn set_accuracy(&mut self, accuracy: f64) {
+        let accuracy = accuracy.clamp(0.0, 1.0);
+
+        if self.miss_probability() <= 0.0 {
+            // No incorrect answers to scale, assume single ones
+            self.weights.resize(2, 0.0);
+        }
+
+        let incorrect_weight = self.total - self.weights[0];
+        self.weights[0] = accuracy * self.total;
+        for weight in &mut self.weights[1..] {
+            *weight = if incorrect_weight > 0.0 {
+                *weight / incorrect_weight * (1.0 - accuracy) * self.total
+            } else {
+                (1.0 - accuracy) * self.total
+            };
+        }
+    }
+
+    /// Samples the number of incorrect answers for a subject whose odds of
+    /// getting the question wrong are multiplied by `difficulty`.
+    fn sample(&self, difficulty: f64, rng: &mut impl Rng) -> u32 {
+        let miss_probability = self.miss_probability();
+        let miss_odds = miss_probability * difficulty;
+        if miss_odds <= 0.0 {
+            return 0;
+        }
+
+        // Always wrong, whatever the difficulty. Otherwise the odds are
+        // infinite, and rounding can push the probability out of range.
+        if miss_probability < 1.0 {
+            let p_miss = (miss_odds / (miss_odds + 1.0 - miss_probability)).clamp(0.0, 1.0);
+            if !rng.gen_bool(p_miss) {
+                return 0;
+            }
+        }
+
+        let mut x = rng.gen_range(0.0..self.total - self.weights[0]);
+
+        for (incorrect_count, weight) in self.weights.iter().enumerate().skip(1) {
+            if x < *weight {
+                return incorrect_count as u32;
+            } else {
+                x -= weight;
+            }
+        }
+
+        // Only reachable through rounding errors
+        (self.weights.len() - 1) as u32
+    }
+}
+
+/// Incorrect answers during reviews for one subject kind and SRS stage. The
+/// meaning and reading questions are assumed to be independent.
+#[derive(Clone, Debug, Default)]
+struct StageAccuracy {
+    meaning: IncorrectCountDistribution,
+    reading: IncorrectCountDistribution,
+}
+
+impl StageAccuracy {
+    /// Returns the accuracy of a typical user.
+    fn population() -> Self {
+        Self {
+            meaning: IncorrectCountDistribution::from_probs(&POPULATION_MEANING_PROBS),
+            reading: IncorrectCountDistribution::from_probs(&POPULATION_READING_PROBS),
+        }
+    }
+
+    fn add(&mut self, incorrect_answers: IncorrectAnswers, weight: f64) {
+        self.meaning.add(incorrect_answers.meaning, weight);
+        self.reading.add(incorrect_answers.reading, weight);
+    }
+
+    /// Returns all the given accuracies combined.
+    fn pooled(accuracies: &[Self]) -> Self {
+        let mut pooled = Self::default();
+        for accuracy in accuracies {
+            pooled.add_prior(accuracy, accuracy.meaning.total);
+        }
+        pooled
+    }
+
+    /// Adds `strength` reviews' worth of weight, distributed like `prior`.
+    fn add_prior(&mut self, prior: &Self, strength: f64) {
+        self.meaning.add_prior(&prior.meaning, strength);
+        self.reading.add_prior(&prior.reading, strength);
+    }
+
+    fn is_empty(&self) -> bool {
+        self.meaning.is_empty()
+    }
+
+    fn question_mut(&mut self, question: Question) -> &mut IncorrectCountDistribution {
+        match question {
+            Question::Meaning => &mut self.meaning,
+            Question::Reading => &mut self.reading,
+        }
+    }
+
+    /// Returns the probability of a review with at least one incorrect answer.
+    fn miss_probability(&self) -> f64 {
+        1.0 - (1.0 - self.meaning.miss_probability()) * (1.0 - self.reading.miss_probability())
+    }
+
+    /// Sets the probability of a review without incorrect answers. The
+    /// meaning and reading accuracies are changed by the same factor in
+    /// log-space, so that the harder question stays the harder one.
+    fn set_accuracy(&mut self, accuracy: f64, has_reading: bool) {
+        if !has_reading {
+            self.meaning.set_accuracy(accuracy);
+            return;
+        }
+
+        // Avoid infinite logarithms for questions that are always wrong
+        let log_accuracy = |distribution: &IncorrectCountDistribution| {
+            (1.0 - distribution.miss_probability()).max(1e-9).ln()
+        };
+        let log_meaning = log_accuracy(&self.meaning);
+        let log_reading = log_accuracy(&self.reading);
+        let meaning_share = if log_meaning + log_reading < 0.0 {
+            log_meaning / (log_meaning + log_reading)
+        } else {
+            0.5
+        };
+
+        self.meaning.set_accuracy(accuracy.powf(meaning_share));
+        self.reading
+            .set_accuracy(accuracy.powf(1.0 - meaning_share));
+    }
+
+    /// Samples the incorrect answers for a subject whose odds of getting each
+    /// question wrong are multiplied by `difficulty`.
+    fn sample(&self, difficulty: f64, rng: &mut impl Rng) -> IncorrectAnswers {
+        IncorrectAnswers {
+            meaning: self.meaning.sample(difficulty, rng),
+            reading: self.reading.sample(difficulty, rng),
+        }
+    }
+}
+
+type StageDistributions = [StageAccuracy; NUM_STAGES];
+
+/// A review of a subject from the cache, as used to estimate difficulties and
+/// the effect of lateness.
+struct SubjectReview<'a> {
+    subject: &'a Subject,
+    stage: Stage,
+    time: DateTime<Local>,
+    /// Hours between the previous review and this one, if done on time
+    interval_hours: Option<u32>,
+    missed: bool,
+    /// Zero for reviews that are ignored by the recency weighting, but whose
+    /// time is still needed
+    weight: f64,
+}
+
+/// Returns the lateness class of a review done `elapsed_hours` after the
+/// previous one, when it was due after `interval_hours`.
+fn lateness_class(elapsed_hours: f64, interval_hours: f64) -> usize {
+    let ratio = elapsed_hours / interval_hours;
+    LATENESS_THRESHOLDS
+        .iter()
+        .filter(|threshold| ratio >= **threshold)
+        .count()
+}
+
+/// How many answers the user gets wrong during a review. The stage after the
+/// review follows from that with `Srs::stage_after_review`, so that stages
+/// without any reviews yet can still be predicted.
+#[derive(Debug)]
+pub struct ReviewResultProbability {
+    /// Indexed by subject kind, then by stage before the review
+    by_kind: [StageDistributions; NUM_SUBJECT_KINDS],
+    /// Difficulties of the subjects that were reviewed. A difficulty
+    /// multiplies the odds of getting each question wrong, 1 being average.
+    difficulties: HashMap<SubjectID, f64>,
+    /// For subjects without reviews: the difficulties to draw from, indexed by
+    /// subject kind, then by level
+    difficulty_pools: [Vec<Vec<f64>>; NUM_SUBJECT_KINDS],
+    /// Multiplies the odds of getting each question wrong, for each lateness
+    /// class
+    lateness_factors: [f64; NUM_LATENESS_CLASSES],
+}
+
+impl ReviewResultProbability {
+    /// Builds separate distributions for each subject kind, smoothed towards
+    /// the prior. Kinds without any reviews yet use the distributions of all
+    /// reviews.
+    ///
+    /// The assignments give the times of the lessons, which are needed to
+    /// know how late the first reviews were.
+    pub fn new(
+        reviews: impl Iterator<Item = Result<Review>>,
+        subjects: &HashMap<SubjectID, Subject>,
+        srs_systems: &HashMap<SrsID, Srs>,
+        assignments: &[(RowRef, Assignment)],
+        weighting: &RecencyWeighting,
+        prior: &AccuracyPrior,
+    ) -> Result<Self> {
+        let mut all_reviews = StageDistributions::default();
+        let mut by_kind = <[StageDistributions; NUM_SUBJECT_KINDS]>::default();
+        let mut num_ignored = 0;
+        let mut subject_reviews = vec![];
+        for review in reviews {
+            let review = review?;
+            let weight = weighting.weight(review.created_at);
+            let stage = review.start_stage as usize;
+            let subject = subjects.get(&review.subject_id);
+            match weight {
+                Some(weight) => {
+                    let all_stage_reviews = &mut all_reviews[stage];
+                    all_stage_reviews
+                        .meaning
+                        .add(review.incorrect_answers.meaning, weight);
+                    // Radicals have no reading, so their reviews say nothing
+                    // about reading accuracy.
+                    if subject.is_some_and(|subject| subject.kind != SubjectKind::Radical) {
+                        all_stage_reviews
+                            .reading
+                            .add(review.incorrect_answers.reading, weight);
+                    }
+                }
+                None => num_ignored += 1,
+            }
+
+            // Reviews of subjects missing from the cache still count towards
+            // the overall meaning distributions.
+            if let Some(subject) = subject {
+                if let Some(weight) = weight {
+                    by_kind[subject.kind as usize][stage].add(review.incorrect_answers, weight);
+                }
+
+                subject_reviews.push(SubjectReview {
+                    subject,
+                    stage: review.start_stage,
+                    time: review.created_at,
+                    interval_hours: srs_systems
+                        .get(&review.srs)
+                        .and_then(|srs| srs.hours_to_next_review(review.start_stage)),
+                    missed: review.incorrect_answers.total() > 0,
+                    weight: weight.unwrap_or(0.0),
+                });
+            }
+        }
+
+        if StageAccuracy::pooled(&all_reviews).is_empty() {
+            return Err(match weighting.window_days {
+                Some(window_days) if num_ignored > 0 => WksimError::NoRecentReviews(window_days),
+                _ => WksimError::NoReviews,
+            });
+        }
+
+        for (kind, distributions) in SUBJECT_KINDS.iter().zip(&mut by_kind) {
+            if StageAccuracy::pooled(distributions).is_empty() {
+                *distributions = all_reviews.clone();
+            }
+
+            let baseline = if prior.population {
+                StageAccuracy::population()
+            } else {
+                StageAccuracy::pooled(distributions)
+            };
+            for distribution in distributions.iter_mut() {
+                distribution.add_prior(&baseline, prior.strength);
+            }
+
+            // Only needed without a prior
+            Self::fill_missing_stages(distributions);
+
+            for distribution in distributions.iter_mut() {
+                if *kind == SubjectKind::Radical {
+                    // Radicals only have a meaning to answer
+                    distribution.reading = IncorrectCountDistribution::never_wrong();
+                } else if distribution.reading.is_empty() {
+                    // No readings reviewed yet, e.g. after only doing
+                    // radicals, so assume a typical user's accuracy.
+                    distribution.reading =
+                        IncorrectCountDistribution::from_probs(&POPULATION_READING_PROBS);
+                }
+            }
+        }
+
+        let expected_miss = |review: &SubjectReview| {
+            review.weight
+                * by_kind[review.subject.kind as usize][review.stage as usize].miss_probability()
+        };
+
+        // Compare each subject's incorrect reviews with how many were expected
+        // for its kind and stages.
+        let mut misses: HashMap<SubjectID, (f64, f64)> = HashMap::new();
+        for review in subject_reviews.iter().filter(|review| review.weight > 0.0) {
+            let (observed, expected) = misses.entry(review.subject.id).or_default();
+            if review.missed {
+                *observed += review.weight;
+            }
+            *expected += expected_miss(review);
+        }
+        let difficulties = misses
+            .into_iter()
+            .map(|(subject_id, (observed, expected))| {
+                let difficulty =
+                    (observed + DIFFICULTY_PRIOR_MISSES) / (expected + DIFFICULTY_PRIOR_MISSES);
+                (subject_id, difficulty)
+            })
+            .collect::<HashMap<_, _>>();
+
+        let mut difficulty_pools = <[Vec<Vec<f64>>; NUM_SUBJECT_KINDS]>::default();
+        for (kind, pools) in SUBJECT_KINDS.iter().zip(&mut difficulty_pools) {
+            let mut kind_difficulties = difficulties
+                .iter()
+                .filter(|(subject_id, _difficulty)| subjects[subject_id].kind == *kind)
+                .map(|(subject_id, difficulty)| {
+                    (subjects[subject_id].level, *subject_id, *difficulty)
+                })
+                .collect::<Vec<_>>();
+            // Sort by ID first, so that pools don't depend on HashMap order
+            kind_difficulties.sort_unstable_by_key(|(_level, subject_id, _difficulty)| *subject_id);
+
+            *pools = (0..=MAX_LEVEL)
+                .map(|level| {
+                    kind_difficulties.sort_by_key(|(subject_level, _subject_id, _difficulty)| {
+                        (i16::from(*subject_level) - i16::from(level)).abs()
+                    });
+                    kind_difficulties
+                        .iter()
+                        .take(DIFFICULTY_POOL_SIZE)
+                        .map(|(_level, _subject_id, difficulty)| *difficulty)
+                        .collect()
+                })
+                .collect();
+        }
+
+        // Same for reviews grouped by how late they were, compared to when
+        // they became available after the previous review or the lesson.
+        let lesson_times = assignments
+            .iter()
+            .filter_map(|(_row, assignment)| Some((assignment.subject_id, assignment.started_at?)))
+            .collect::<HashMap<_, _>>();
+        subject_reviews.sort_by_key(|review| (review.subject.id, review.time));
+        let mut lateness_misses = [(0.0, 0.0); NUM_LATENESS_CLASSES];
+        let mut previous_review: Option<&SubjectReview> = None;
+        for review in &subject_reviews {
+            let previous_time = match previous_review {
+                Some(previous) if previous.subject.id == review.subject.id => Some(previous.time),
+                _ => lesson_times.get(&review.subject.id).copied(),
+            };
+            previous_review = Some(review);
+
+            let (previous_time, interval_hours) = match (previous_time, review.interval_hours) {
+                (Some(time), Some(hours)) if hours > 0 => (time, hours),
+                _ => continue,
+            };
+            let elapsed_hours = review
+                .time
+                .signed_duration_since(previous_time)
+                .num_minutes() as f64
+                / 60.0;
+            let (observed, expected) =
+                &mut lateness_misses[lateness_class(elapsed_hours, interval_hours.into())];
+            if review.missed {
+                *observed += review.weight;
+            }
+            *expected += expected_miss(review);
+        }
+        let lateness_factors = lateness_misses.map(|(observed, expected)| {
+            (observed + LATENESS_PRIOR_MISSES) / (expected + LATENESS_PRIOR_MISSES)
+        });
+
+        Ok(Self {
+            by_kind,
+            difficulties,
+            difficulty_pools,
+            lateness_factors,
+        })
+    }
+
+    /// Returns how much the odds of getting a review wrong are multiplied
+    /// when it's done `elapsed_hours` after the previous one, instead of after
+    /// `interval_hours`.
+    pub fn lateness_factor(&self, elapsed_hours: f64, interval_hours: f64) -> f64 {
+        self.lateness_factors[lateness_class(elapsed_hours, interval_hours)]
+    }
+
+    /// Changes the probability of answering the question correctly the first
+    /// time by `change`, e.g. 0.05 to simulate a 5% better accuracy.
+    pub fn adjust_accuracy(&mut self, question: Question, change: f64) {
+        for (kind, distributions) in SUBJECT_KINDS.iter().zip(&mut self.by_kind) {
+            if question == Question::Reading && *kind == SubjectKind::Radical {
+                continue;
+            }
+
+            for distribution in distributions.iter_mut() {
+                distribution.question_mut(question).adjust_accuracy(change);
+            }
+        }
+    }
+
+    /// Replaces the estimated accuracy with the given one, for the subject
+    /// kinds and stages it applies to.
+    pub fn override_accuracy(&mut self, accuracy_override: &AccuracyOverride) {
+        for (kind, distributions) in SUBJECT_KINDS.iter().zip(&mut self.by_kind) {
+            for (stage, distribution) in distributions.iter_mut().enumerate() {
+                let stage = Stage::try_from(u8::try_from(stage).unwrap()).unwrap();
+                if accuracy_override.scope.contains(*kind, stage) {
+                    distribution
+                        .set_accuracy(accuracy_override.accuracy, *kind != SubjectKind::Radical);
+                }
+            }
+        }
+    }
+
+    /// Returns the difficulty of a subject, based on its own reviews if there
+    /// are any, or drawn from those of reviewed subjects of the same kind and
+    /// similar levels otherwise.
+    pub fn sample_difficulty(&self, subject: &Subject, rng: &mut impl Rng) -> f64 {
+        if let Some(difficulty) = self.difficulties.get(&subject.id) {
+            return *difficulty;
+        }
+
+        let pool = &self.difficulty_pools[subject.kind as usize][usize::from(subject.level)];
+        if pool.is_empty() {
+            1.0
+        } else {
+            pool[rng.gen_range(0..pool.len())]
+        }
+    }
+
+    /// Gives stages without reviews the distribution of the closest lower
+    /// stage with reviews, or failing that the closest higher one.
+    fn fill_missing_stages(distributions: &mut StageDistributions) {
+        let known_stages = (0..NUM_STAGES)
+            .filter(|stage| !distributions[*stage].is_empty())
+            .collect::<Vec<_>>();
+        if known_stages.is_empty() {
+            return;
+        }
+
+        for stage in 0..NUM_STAGES {
+            if distributions[stage].is_empty() {
+                let closest_stage = known_stages
+                    .iter()
+                    .rev()
+                    .find(|known_stage| **known_stage < stage)
+                    .or_else(|| known_stages.first())
+                    .copied()
+                    .unwrap();
+                distributions[stage] = distributions[closest_stage].clone();
+            }
+        }
+    }
+
+    pub fn sample_for(
+        &self,
+        subject: &Subject,
+        prev_stage: Stage,
+        difficulty: f64,
+        rng: &mut impl Rng,
+    ) -> IncorrectAnswers {
+        self.by_kind[subject.kind as usize][prev_stage as usize].sample(difficulty, rng)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone};
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha8Rng;
+
+    use super::*;
+
+    const SRS: SrsID = SrsID(1);
+    const DIFFICULTIES: [f64; 3] = [0.5, 1.0, 3.0];
+
+    fn srs_systems() -> HashMap<SrsID, Srs> {
+        let hours = [4, 8, 23, 47, 167, 335, 719, 2879];
+        let mut intervals = vec![None];
+        intervals.extend(hours.iter().map(|hours| Some(*hours)));
+        intervals.push(None);
+
+        let srs = Srs {
+            id: SRS,
+            intervals,
+            unlocking_stage: Stage::Initiate,
+            starting_stage: Stage::Apprentice1,
+            passing_stage: Stage::Guru1,
+            burning_stage: Stage::Burned,
+        };
+        vec![(SRS, srs)].into_iter().collect()
+    }
+
+    fn subjects() -> HashMap<SubjectID, Subject> {
+        SUBJECT_KINDS
+            .iter()
+            .enumerate()
+            .map(|(i, kind)| {
+                let id = SubjectID(i as u16 + 1);
+                let subject = Subject {
+                    id,
+                    level: 1,
+                    kind: *kind,
+                    depends_on: vec![],
+                    depended_on_by: vec![],
+                    srs: SRS,
+                };
+                (id, subject)
+            })
+            .collect()
+    }
+
+    /// Builds the model from a mix of right and wrong reviews of every
+    /// subject, at every reviewed stage.
+    fn review_prob(subjects: &HashMap<SubjectID, Subject>) -> ReviewResultProbability {
+        let now = Local.ymd(2021, 6, 1).and_hms(0, 0, 0);
+        let mut reviews = vec![];
+        for subject in subjects.values() {
+            for stage in 1..u8::from(Stage::Burned) {
+                for i in 0..4 {
+                    let has_reading = subject.kind != SubjectKind::Radical;
+                    reviews.push(Review {
+                        subject_id: subject.id,
+                        srs: SRS,
+                        created_at: now - Duration::days(i64::from(stage) * 4 + i),
+                        start_stage: Stage::try_from(stage).unwrap(),
+                        end_stage: Stage::try_from(stage).unwrap(),
+                        incorrect_answers: IncorrectAnswers {
+                            meaning: u32::from(i == 0),
+                            reading: u32::from(i == 1 && has_reading),
+                        },
+                    });
+                }
+            }
+        }
+
+        ReviewResultProbability::new(
+            reviews.into_iter().map(Ok),
+            subjects,
+            &srs_systems(),
+            &[],
+            &RecencyWeighting {
+                now,
+                window_days: None,
+                half_life_days: None,
+            },
+            &AccuracyPrior {
+                strength: 10.0,
+                population: false,
+            },
+        )
+        .unwrap()
+    }
+
+    /// Samples reviews of every subject at every reviewed stage, with a
+    /// range of difficulties.
+    fn sample_all(
+        review_prob: &ReviewResultProbability,
+        subjects: &HashMap<SubjectID, Subject>,
+    ) -> Vec<(SubjectKind, IncorrectAnswers)> {
+        let mut rng = ChaCha8Rng::seed_from_u64(1);
+        let mut samples = vec![];
+        for subject in subjects.values() {
+            for stage in 1..u8::from(Stage::Burned) {
+                for difficulty in &DIFFICULTIES {
+                    for _ in 0..20 {
+                        let stage = Stage::try_from(stage).unwrap();
+                        let incorrect_answers =
+                            review_prob.sample_for(subject, stage, *difficulty, &mut rng);
+                        samples.push((subject.kind, incorrect_answers));
+                    }
+                }
+            }
+        }
+        samples
+    }
+
+    #[test]
+    fn override_accuracy_extremes() {
+        let subjects = subjects();
+
+        let mut never_right = review_prob(&subjects);
+        never_right.override_accuracy(&"0".parse().unwrap());
+        for (_kind, incorrect_answers) in sample_all(&never_right, &subjects) {
+            assert!(incorrect_answers.total() > 0);
+        }
+
+        let mut always_right = review_prob(&subjects);
+        always_right.override_accuracy(&"1".parse().unwrap());
+        for (_kind, incorrect_answers) in sample_all(&always_right, &subjects) {
+            assert_eq!(incorrect_answers.total(), 0);
+        }
+
+        let mut kanji_never_right = review_prob(&subjects);
+        kanji_never_right.override_accuracy(&"kanji=0".parse().unwrap());
+        for (kind, incorrect_answers) in sample_all(&kanji_never_right, &subjects) {
+            if kind == SubjectKind::Kanji {
+                assert!(incorrect_answers.total() > 0);
+            }
+        }
+    }
+
+    #[test]
+    fn adjust_accuracy_clamps() {
+        let subjects = subjects();
+
+        let mut review_prob = review_prob(&subjects);
+        review_prob.adjust_accuracy(Question::Reading, -2.0);
+        review_prob.adjust_accuracy(Question::Meaning, 2.0);
+        for (kind, incorrect_answers) in sample_all(&review_prob, &subjects) {
+            assert_eq!(incorrect_answers.meaning, 0);
+            if kind == SubjectKind::Radical {
+                assert_eq!(incorrect_answers.reading, 0);
+            } else {
+                assert!(incorrect_answers.reading > 0);
+            }
+        }
+    }
+}
@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, Local, TimeZone};
+
+use crate::{
+    error::{Result, RowRef, WksimError},
+    model::{Assignment, Review, Srs, SrsID, Subject, SubjectID},
+};
+
+/// Returns the assignments as they were at `time`, reconstructed from the
+/// review history. Subjects that weren't unlocked yet at that time are left
+/// out.
+pub fn assignments_at(
+    time: DateTime<Local>,
+    assignments: &[(RowRef, Assignment)],
+    reviews: &[Review],
+    subjects: &HashMap<SubjectID, Subject>,
+    srs_systems: &HashMap<SrsID, Srs>,
+) -> Result<Vec<(RowRef, Assignment)>> {
+    let mut last_reviews: HashMap<SubjectID, &Review> = HashMap::new();
+    for review in reviews.iter().filter(|review| review.created_at < time) {
+        let last_review = last_reviews.entry(review.subject_id).or_insert(review);
+        if review.created_at > last_review.created_at {
+            *last_review = review;
+        }
+    }
+
+    let mut past_assignments = vec![];
+    for (row, assignment) in assignments {
+        match assignment.unlocked_at {
+            Some(unlocked_at) if unlocked_at <= time => {}
+            _ => continue,
+        }
+
+        let subject = subjects
+            .get(&assignment.subject_id)
+            .ok_or(WksimError::UnknownSubject {
+                row: *row,
+                subject_id: assignment.subject_id,
+            })?;
+        let srs = srs_systems
+            .get(&subject.srs)
+            .ok_or(WksimError::UnknownSrs {
+                row: RowRef {
+                    table: "subjects",
+                    id: subject.id.0.into(),
+                },
+                srs: subject.srs,
+            })?;
+
+        // The stage after the last review, or after the lesson if there was
+        // no review yet.
+        let last_change = match (last_reviews.get(&subject.id), assignment.started_at) {
+            (Some(review), _) => Some((review.end_stage, review.created_at)),
+            (None, Some(started_at)) if started_at <= time => {
+                Some((srs.starting_stage, started_at))
+            }
+            _ => None,
+        };
+
+        let (stage, next_review_time) = match last_change {
+            Some((stage, changed_at)) => {
+                let next_review_time = srs
+                    .hours_to_next_review(stage)
+                    .map(|hours| changed_at + Duration::hours(hours.into()));
+                (stage, next_review_time)
+            }
+            None => (srs.unlocking_stage, None),
+        };
+
+        past_assignments.push((
+            *row,
+            Assignment {
+                stage,
+                next_review_time,
+                ..assignment.clone()
+            },
+        ));
+    }
+
+    Ok(past_assignments)
+}
+
+/// Returns the start of the given day of a simulation that starts at
+/// `base_time`. Day 0 starts at `base_time`, and later days at local midnight.
+fn day_start(base_time: DateTime<Local>, day: usize) -> DateTime<Local> {
+    if day == 0 {
+        return base_time;
+    }
+
+    let date = base_time.date().naive_local() + Duration::days(day as i64);
+    Local
+        .from_local_datetime(&date.and_hms(0, 0, 0))
+        .earliest()
+        // Midnight is skipped by DST changes in some time zones
+        .unwrap_or_else(|| base_time + Duration::days(day as i64))
+}
+
+/// What actually happened after the start of a backtest, for each local
+/// calendar day since `base_time`.
+pub struct ActualHistory {
+    /// Level at the start of each day
+    pub levels: Vec<u32>,
+    /// Number of reviews done each day. The simulator counts lessons as
+    /// reviews, so lessons started that day are included.
+    pub reviews: Vec<u32>,
+}
+
+impl ActualHistory {
+    pub fn new(
+        base_time: DateTime<Local>,
+        num_days: usize,
+        assignments: &[(RowRef, Assignment)],
+        reviews: &[Review],
+        subjects: &HashMap<SubjectID, Subject>,
+    ) -> Self {
+        let start_date = base_time.date().naive_local();
+        let day_of = |time: DateTime<Local>| {
+            if time < base_time {
+                None
+            } else {
+                let day = (time.date().naive_local() - start_date).num_days() as usize;
+                Some(day).filter(|day| *day < num_days)
+            }
+        };
+
+        let mut daily_reviews = vec![0; num_days];
+        let review_times = reviews.iter().map(|review| review.created_at);
+        let lesson_times = assignments
+            .iter()
+            .filter_map(|(_row, assignment)| assignment.started_at);
+        for time in review_times.chain(lesson_times) {
+            if let Some(day) = day_of(time) {
+                daily_reviews[day] += 1;
+            }
+        }
+
+        let levels = (0..num_days)
+            .map(|day| {
+                let day_start = day_start(base_time, day);
+                assignments
+                    .iter()
+                    .filter(|(_row, assignment)| {
+                        assignment
+                            .unlocked_at
+                            .is_some_and(|unlocked_at| unlocked_at <= day_start)
+                    })
+                    .filter_map(|(_row, assignment)| subjects.get(&assignment.subject_id))
+                    .map(|subject| u32::from(subject.level))
+                    .max()
+                    .unwrap_or(0)
+            })
+            .collect();
+
+        Self {
+            levels,
+            reviews: daily_reviews,
+        }
+    }
+}
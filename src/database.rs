@@ -1,166 +1,430 @@
 use chrono::{DateTime, Local};
-use rusqlite::{Connection, OptionalExtension, Statement};
-use std::convert::{TryFrom, TryInto};
+use rusqlite::{Connection, Row, Statement};
+use std::{
+    convert::{TryFrom, TryInto},
+    env, fs,
+    path::{Path, PathBuf},
+};
 
-use crate::model::{Assignment, Review, Srs, Stage, Subject, SubjectID};
+use crate::{
+    error::{Result, RowRef, WksimError},
+    model::{
+        Assignment, IncorrectAnswers, Review, Srs, SrsID, Stage, Subject, SubjectID, SubjectKind,
+    },
+};
 
-const DB_PATH: &str = "wanikani_cache.db";
+/// Cache used when neither a database path nor a profile is given.
+pub const DEFAULT_DB_PATH: &str = "wanikani_cache.db";
 
-pub fn open() -> rusqlite::Result<Connection> {
-    Connection::open(DB_PATH)
+/// Tables that mirror the WaniKani API resources of the same name. Each row
+/// holds the full JSON resource as returned by the API.
+pub const OBJECT_TYPES: [&str; 4] = [
+    "reviews",
+    "subjects",
+    "assignments",
+    "spaced_repetition_systems",
+];
+
+/// Returns the base directory for wksim's data, following the XDG base
+/// directory spec.
+fn data_dir() -> Option<PathBuf> {
+    let xdg_data_home = env::var_os("XDG_DATA_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        // The spec says relative paths should be ignored
+        .filter(|dir| dir.is_absolute());
+
+    let data_home = match xdg_data_home {
+        Some(dir) => dir,
+        None => PathBuf::from(env::var_os("HOME")?).join(".local/share"),
+    };
+
+    Some(data_home.join("wksim"))
+}
+
+/// Returns the cache path for the given user profile.
+pub fn profile_path(profile: &str) -> Result<PathBuf> {
+    let is_valid = !profile.is_empty()
+        && profile
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '-' || c == '_' || c == '.')
+        && !profile.starts_with('.');
+    if !is_valid {
+        return Err(WksimError::InvalidProfile(profile.to_string()));
+    }
+
+    let data_dir = data_dir().ok_or(WksimError::NoDataDir)?;
+    Ok(data_dir.join("profiles").join(format!("{}.db", profile)))
+}
+
+/// Opens the cache at the given path, creating its parent directory if
+/// needed.
+pub fn open(path: &Path) -> Result<Connection> {
+    if let Some(parent) = path.parent() {
+        if !parent.as_os_str().is_empty() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+
+    Ok(Connection::open(path)?)
+}
+
+pub fn create_tables(conn: &Connection) -> Result<()> {
+    for obj_type in &OBJECT_TYPES {
+        conn.execute_batch(&format!(
+            "CREATE TABLE IF NOT EXISTS {}(
+                id integer,
+                object text,
+                data json,
+                primary key(id)
+            );",
+            obj_type
+        ))?;
+    }
+
+    Ok(())
+}
+
+/// Returns the latest `data_updated_at` timestamp of the cached objects of
+/// the given type, as the raw string stored by the API.
+pub fn last_object_time(conn: &Connection, obj_type: &str) -> Result<Option<String>> {
+    conn.query_row(
+        &format!(
+            "SELECT max(json_extract(data, '$.data_updated_at')) FROM {}",
+            obj_type
+        ),
+        [],
+        |row| row.get(0),
+    )
+    .map_err(WksimError::from)
+}
+
+/// Inserts or replaces the given objects. Meant to be called in a transaction
+/// that covers all pages of a collection.
+pub fn insert_objects(
+    conn: &Connection,
+    obj_type: &str,
+    objects: &[serde_json::Value],
+) -> Result<()> {
+    let mut stmt = conn.prepare_cached(&format!(
+        "INSERT OR REPLACE INTO {}(id, object, data) VALUES(?, ?, ?)",
+        obj_type
+    ))?;
+
+    for object in objects {
+        stmt.execute(rusqlite::params![
+            object["id"].as_i64(),
+            object["object"].as_str(),
+            object,
+        ])?;
+    }
+
+    Ok(())
+}
+
+fn table_exists(conn: &Connection, table: &str) -> Result<bool> {
+    let count: i64 = conn.query_row(
+        "SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = ?",
+        [table],
+        |row| row.get(0),
+    )?;
+    Ok(count > 0)
+}
+
+/// Reads the row id and the `data` object of a cached API resource, from a
+/// query selecting `id, data`.
+fn row_to_json(table: &'static str, row: &Row) -> Result<(RowRef, serde_json::Value)> {
+    let row_ref = RowRef {
+        table,
+        id: row.get(0)?,
+    };
+
+    let text: String = row.get(1)?;
+    let mut json: serde_json::Value =
+        serde_json::from_str(&text).map_err(|_| WksimError::MalformedJson {
+            row: row_ref,
+            field: "data",
+        })?;
+
+    match json.get_mut("data") {
+        Some(data) if data.is_object() => Ok((row_ref, data.take())),
+        _ => Err(WksimError::MalformedJson {
+            row: row_ref,
+            field: "data",
+        }),
+    }
+}
+
+fn json_to_i64(row: RowRef, json: &serde_json::Value, field: &'static str) -> Result<i64> {
+    json[field]
+        .as_i64()
+        .ok_or(WksimError::MalformedJson { row, field })
+}
+
+fn json_to_u32(row: RowRef, json: &serde_json::Value, field: &'static str) -> Result<u32> {
+    let value = json_to_i64(row, json, field)?;
+    u32::try_from(value).map_err(|_| WksimError::MalformedJson { row, field })
+}
+
+fn json_to_srs_id(row: RowRef, json: &serde_json::Value, field: &'static str) -> Result<SrsID> {
+    let id = json_to_i64(row, json, field)?;
+    let id = u16::try_from(id).map_err(|_| WksimError::MalformedJson { row, field })?;
+    Ok(SrsID(id))
+}
+
+fn json_to_stage(row: RowRef, json: &serde_json::Value, field: &'static str) -> Result<Stage> {
+    let stage = json_to_i64(row, json, field)?;
+    u8::try_from(stage)
+        .ok()
+        .and_then(|value| Stage::try_from(value).ok())
+        .ok_or(WksimError::UnknownStage { row, stage })
+}
+
+/// Converts an SRS stage interval to a number of hours. Returns None for
+/// stages without an interval.
+fn json_to_interval_hours(row: RowRef, json: &serde_json::Value) -> Result<Option<u32>> {
+    let malformed = |field| WksimError::MalformedJson { row, field };
+
+    let interval = match &json["interval"] {
+        serde_json::Value::Null => return Ok(None),
+        interval => interval.as_u64().ok_or_else(|| malformed("interval"))?,
+    };
+
+    let milliseconds_per_unit = match json["interval_unit"].as_str() {
+        Some("milliseconds") => 1,
+        Some("seconds") => 1000,
+        Some("minutes") => 60 * 1000,
+        Some("hours") => 3600 * 1000,
+        Some("days") => 24 * 3600 * 1000,
+        Some("weeks") => 7 * 24 * 3600 * 1000,
+        _ => return Err(malformed("interval_unit")),
+    };
+
+    let hours = interval
+        .checked_mul(milliseconds_per_unit)
+        .ok_or_else(|| malformed("interval"))?
+        / (3600 * 1000);
+    Ok(Some(hours.try_into().map_err(|_| malformed("interval"))?))
+}
+
+fn json_to_subject_id(
+    row: RowRef,
+    json: &serde_json::Value,
+    field: &'static str,
+) -> Result<SubjectID> {
+    let id = json_to_i64(row, json, field)?;
+    let id = u16::try_from(id).map_err(|_| WksimError::MalformedJson { row, field })?;
+    Ok(SubjectID(id))
+}
+
+/// Reads an optional list of subject IDs, returning an empty list if the field
+/// is missing.
+fn json_to_subject_id_list(
+    row: RowRef,
+    json: &serde_json::Value,
+    field: &'static str,
+) -> Result<Vec<SubjectID>> {
+    let list = match json.get(field) {
+        None => return Ok(vec![]),
+        Some(list) => list
+            .as_array()
+            .ok_or(WksimError::MalformedJson { row, field })?,
+    };
+
+    list.iter()
+        .map(|value| {
+            value
+                .as_i64()
+                .and_then(|id| u16::try_from(id).ok())
+                .map(SubjectID)
+                .ok_or(WksimError::MalformedJson { row, field })
+        })
+        .collect()
+}
+
+fn json_to_time(
+    row: RowRef,
+    json: &serde_json::Value,
+    field: &'static str,
+) -> Result<Option<DateTime<Local>>> {
+    match &json[field] {
+        serde_json::Value::Null => Ok(None),
+        serde_json::Value::String(time) => DateTime::parse_from_rfc3339(time)
+            .map(|time| Some(time.into()))
+            .map_err(|_| WksimError::MalformedJson { row, field }),
+        _ => Err(WksimError::MalformedJson { row, field }),
+    }
 }
 
 pub struct DatabaseWrapper<'a> {
     select_reviews_stmt: Statement<'a>,
     select_subjects_stmt: Statement<'a>,
     select_assignments_stmt: Statement<'a>,
-    select_next_review_time_stmt: Statement<'a>,
+    select_srs_stmt: Statement<'a>,
 }
 
 impl<'a> DatabaseWrapper<'a> {
-    pub fn new(conn: &'a Connection) -> Self {
-        let select_reviews_stmt = conn
-            .prepare("SELECT json_extract(data, '$.data') FROM reviews")
-            .unwrap();
+    pub fn new(conn: &'a Connection) -> Result<Self> {
+        for table in &OBJECT_TYPES {
+            if !table_exists(conn, table)? {
+                return Err(WksimError::MissingTable(table));
+            }
+        }
+
+        let select_reviews_stmt = conn.prepare("SELECT id, data FROM reviews")?;
 
-        let select_subjects_stmt = conn
-            .prepare("SELECT id, object, json_extract(data, '$.data') FROM subjects")
-            .unwrap();
+        let select_subjects_stmt = conn.prepare("SELECT id, data, object FROM subjects")?;
 
-        let select_assignments_stmt = conn
-            .prepare("SELECT json_extract(data, '$.data') FROM assignments")
-            .unwrap();
+        let select_assignments_stmt = conn.prepare("SELECT id, data FROM assignments")?;
 
-        let select_next_review_time_stmt = conn
-            .prepare("SELECT min(json_extract(data, '$.data.available_at')) FROM assignments")
-            .unwrap();
+        let select_srs_stmt = conn.prepare("SELECT id, data FROM spaced_repetition_systems")?;
 
-        Self {
+        Ok(Self {
             select_reviews_stmt,
             select_subjects_stmt,
             select_assignments_stmt,
-            select_next_review_time_stmt,
-        }
+            select_srs_stmt,
+        })
     }
 
-    fn json_to_srs(value: &serde_json::Value) -> Srs {
-        u8::try_from(value.as_i64().unwrap())
-            .unwrap()
-            .try_into()
-            .unwrap()
-    }
+    pub fn reviews(&mut self) -> Result<impl Iterator<Item = Result<Review>> + '_> {
+        Ok(self.select_reviews_stmt.query_and_then([], |row| {
+            // TODO: don't set up a full serde_json::Value, avoid copying
+            let (row, json) = row_to_json("reviews", row)?;
+            let subject_id = json_to_subject_id(row, &json, "subject_id")?;
+            let srs = json_to_srs_id(row, &json, "spaced_repetition_system_id")?;
+            let created_at =
+                json_to_time(row, &json, "created_at")?.ok_or(WksimError::MalformedJson {
+                    row,
+                    field: "created_at",
+                })?;
+            let start_stage = json_to_stage(row, &json, "starting_srs_stage")?;
+            let end_stage = json_to_stage(row, &json, "ending_srs_stage")?;
+            let incorrect_answers = IncorrectAnswers {
+                meaning: json_to_u32(row, &json, "incorrect_meaning_answers")?,
+                reading: json_to_u32(row, &json, "incorrect_reading_answers")?,
+            };
 
-    fn json_to_stage(value: &serde_json::Value) -> Stage {
-        u8::try_from(value.as_i64().unwrap())
-            .unwrap()
-            .try_into()
-            .unwrap()
-    }
-
-    fn json_to_subject_id(value: &serde_json::Value) -> SubjectID {
-        SubjectID(value.as_i64().unwrap().try_into().unwrap())
-    }
-
-    fn json_to_subject_id_list(value: &serde_json::Value) -> Vec<SubjectID> {
-        value
-            .as_array()
-            .unwrap()
-            .iter()
-            .map(Self::json_to_subject_id)
-            .collect()
-    }
-
-    pub fn reviews(&mut self) -> impl Iterator<Item = rusqlite::Result<Review>> + '_ {
-        self.select_reviews_stmt
-            .query_map([], |row| {
-                // TODO: don't set up a full serde_json::Value, avoid copying
-                let json: serde_json::Value = row.get(0)?;
-                let srs = Self::json_to_srs(&json["spaced_repetition_system_id"]);
-                let start_stage = Self::json_to_stage(&json["starting_srs_stage"]);
-                let end_stage = Self::json_to_stage(&json["ending_srs_stage"]);
-
-                Ok(Review {
-                    srs,
-                    start_stage,
-                    end_stage,
-                })
+            Ok(Review {
+                subject_id,
+                srs,
+                created_at,
+                start_stage,
+                end_stage,
+                incorrect_answers,
             })
-            .unwrap()
+        })?)
     }
 
-    pub fn subjects(&mut self) -> impl Iterator<Item = rusqlite::Result<Subject>> + '_ {
-        self.select_subjects_stmt
-            .query_map([], |row| {
-                let id = SubjectID(row.get::<_, i64>(0)?.try_into().unwrap());
+    pub fn subjects(&mut self) -> Result<impl Iterator<Item = Result<Subject>> + '_> {
+        Ok(self.select_subjects_stmt.query_and_then([], |row| {
+            let object: String = row.get(2)?;
 
-                let object = row.get_ref(1)?.as_str().unwrap().try_into().unwrap();
+            // TODO: don't set up a full serde_json::Value, avoid copying
+            let (row, json) = row_to_json("subjects", row)?;
 
-                // TODO: don't set up a full serde_json::Value, avoid copying
-                let json: serde_json::Value = row.get(2)?;
+            let id = u16::try_from(row.id)
+                .map(SubjectID)
+                .map_err(|_| WksimError::MalformedJson { row, field: "id" })?;
 
-                let level = json["level"].as_i64().unwrap().try_into().unwrap();
+            let kind = SubjectKind::try_from(object.as_str()).map_err(|()| {
+                WksimError::UnknownSubjectKind {
+                    row,
+                    object: object.clone(),
+                }
+            })?;
 
-                // let kind = serde_json::from_value()
+            let level = json_to_i64(row, &json, "level")?;
+            let level = u8::try_from(level).map_err(|_| WksimError::MalformedJson {
+                row,
+                field: "level",
+            })?;
 
-                let depends_on = json
-                    .get("component_subject_ids")
-                    .map_or(vec![], Self::json_to_subject_id_list);
-                let depended_on_by = json
-                    .get("amalgamation_subject_ids")
-                    .map_or(vec![], Self::json_to_subject_id_list);
+            let depends_on = json_to_subject_id_list(row, &json, "component_subject_ids")?;
+            let depended_on_by = json_to_subject_id_list(row, &json, "amalgamation_subject_ids")?;
 
-                let srs = Self::json_to_srs(&json["spaced_repetition_system_id"]);
+            let srs = json_to_srs_id(row, &json, "spaced_repetition_system_id")?;
 
-                Ok({
-                    Subject {
-                        id,
-                        level,
-                        kind: object,
-                        depends_on,
-                        depended_on_by,
-                        srs,
-                    }
-                })
+            Ok(Subject {
+                id,
+                level,
+                kind,
+                depends_on,
+                depended_on_by,
+                srs,
             })
-            .unwrap()
+        })?)
     }
 
-    pub fn assignments(&mut self) -> impl Iterator<Item = rusqlite::Result<Assignment>> + '_ {
-        self.select_assignments_stmt
-            .query_map([], |row| {
-                // TODO: don't set up a full serde_json::Value, avoid copying
-                let json: serde_json::Value = row.get(0)?;
-                let subject_id = Self::json_to_subject_id(&json["subject_id"]);
-                let stage = Self::json_to_stage(&json["srs_stage"]);
-
-                let next_review_time = &json["available_at"];
-                let next_review_time = if next_review_time.is_null() {
-                    None
-                } else {
-                    let next_review_time = next_review_time.as_str().unwrap();
-                    Some(
-                        DateTime::parse_from_rfc3339(next_review_time)
-                            .unwrap()
-                            .into(),
-                    )
-                };
-
-                Ok(Assignment {
+    /// Returns the assignments, along with the row each one was read from.
+    pub fn assignments(
+        &mut self,
+    ) -> Result<impl Iterator<Item = Result<(RowRef, Assignment)>> + '_> {
+        Ok(self.select_assignments_stmt.query_and_then([], |row| {
+            // TODO: don't set up a full serde_json::Value, avoid copying
+            let (row, json) = row_to_json("assignments", row)?;
+            let subject_id = json_to_subject_id(row, &json, "subject_id")?;
+            let stage = json_to_stage(row, &json, "srs_stage")?;
+            let next_review_time = json_to_time(row, &json, "available_at")?;
+            let unlocked_at = json_to_time(row, &json, "unlocked_at")?;
+            let started_at = json_to_time(row, &json, "started_at")?;
+
+            Ok((
+                row,
+                Assignment {
                     subject_id,
                     stage,
                     next_review_time,
-                })
-            })
-            .unwrap()
+                    unlocked_at,
+                    started_at,
+                },
+            ))
+        })?)
     }
 
-    pub fn next_review_time(&mut self) -> Option<DateTime<Local>> {
-        self.select_next_review_time_stmt
-            .query_row([], |row| {
-                let time = row.get_ref(0)?.as_str().unwrap();
-                Ok(DateTime::parse_from_rfc3339(time).unwrap().into())
+    pub fn spaced_repetition_systems(&mut self) -> Result<impl Iterator<Item = Result<Srs>> + '_> {
+        Ok(self.select_srs_stmt.query_and_then([], |row| {
+            // TODO: don't set up a full serde_json::Value, avoid copying
+            let (row, json) = row_to_json("spaced_repetition_systems", row)?;
+
+            let id = u16::try_from(row.id)
+                .map(SrsID)
+                .map_err(|_| WksimError::MalformedJson { row, field: "id" })?;
+
+            let stages = json["stages"].as_array().ok_or(WksimError::MalformedJson {
+                row,
+                field: "stages",
+            })?;
+
+            // Intervals are indexed by stage position, which must cover
+            // 0..stages.len() exactly once.
+            let malformed_position = || WksimError::MalformedJson {
+                row,
+                field: "position",
+            };
+            let mut intervals = vec![None; stages.len()];
+            for stage in stages {
+                let interval = stage["position"]
+                    .as_u64()
+                    .and_then(|position| usize::try_from(position).ok())
+                    .and_then(|position| intervals.get_mut(position))
+                    .filter(|interval| interval.is_none())
+                    .ok_or_else(malformed_position)?;
+                *interval = Some(json_to_interval_hours(row, stage)?);
+            }
+            let intervals = intervals
+                .into_iter()
+                .collect::<Option<_>>()
+                .ok_or_else(malformed_position)?;
+
+            Ok(Srs {
+                id,
+                intervals,
+                unlocking_stage: json_to_stage(row, &json, "unlocking_stage_position")?,
+                starting_stage: json_to_stage(row, &json, "starting_stage_position")?,
+                passing_stage: json_to_stage(row, &json, "passing_stage_position")?,
+                burning_stage: json_to_stage(row, &json, "burning_stage_position")?,
             })
-            .optional()
-            .unwrap()
+        })?)
     }
 }
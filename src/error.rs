@@ -0,0 +1,112 @@
+use std::fmt;
+
+use chrono::{DateTime, Local};
+
+use crate::model::{SrsID, SubjectID};
+
+/// Identifies a row in one of the cache tables, for error messages.
+#[derive(Clone, Copy, Debug)]
+pub struct RowRef {
+    pub table: &'static str,
+    pub id: i64,
+}
+
+impl fmt::Display for RowRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} row {}", self.table, self.id)
+    }
+}
+
+#[derive(Debug)]
+pub enum WksimError {
+    Database(rusqlite::Error),
+    Io(std::io::Error),
+    InvalidProfile(String),
+    NoDataDir,
+    Api(Box<ureq::Error>),
+    InvalidApiResponse(String),
+    MissingTable(&'static str),
+    MalformedJson { row: RowRef, field: &'static str },
+    UnknownStage { row: RowRef, stage: i64 },
+    UnknownSrs { row: RowRef, srs: SrsID },
+    UnknownSubject { row: RowRef, subject_id: SubjectID },
+    UnknownSubjectKind { row: RowRef, object: String },
+    UnexpectedAvailableAt(RowRef),
+    NoReviews,
+    NoRecentReviews(f64),
+    NoAssignments,
+    NoHistoryAfter(DateTime<Local>),
+}
+
+pub type Result<T> = std::result::Result<T, WksimError>;
+
+impl fmt::Display for WksimError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        use WksimError::*;
+
+        match self {
+            Database(err) => write!(f, "database error: {}", err),
+            Io(err) => write!(f, "I/O error: {}", err),
+            InvalidProfile(profile) => write!(f, "invalid profile name {:?}", profile),
+            NoDataDir => write!(
+                f,
+                "can't find a data directory for profiles, set XDG_DATA_HOME or HOME"
+            ),
+            Api(err) => write!(f, "API request failed: {}", err),
+            InvalidApiResponse(msg) => write!(f, "invalid API response: {}", msg),
+            MissingTable(table) => write!(
+                f,
+                "table {} is missing from the cache, run `wksim sync` first",
+                table
+            ),
+            MalformedJson { row, field } => {
+                write!(f, "{}: missing or malformed field {:?}", row, field)
+            }
+            UnknownStage { row, stage } => write!(f, "{}: unknown SRS stage {}", row, stage),
+            UnknownSrs { row, srs } => {
+                write!(f, "{}: unknown spaced repetition system {}", row, srs.0)
+            }
+            UnknownSubject { row, subject_id } => {
+                write!(f, "{}: unknown subject {}", row, subject_id.0)
+            }
+            UnknownSubjectKind { row, object } => {
+                write!(f, "{}: unknown subject type {:?}", row, object)
+            }
+            UnexpectedAvailableAt(row) => {
+                write!(f, "{}: lesson not done yet, but a review is scheduled", row)
+            }
+            NoReviews => write!(f, "no reviews in the cache"),
+            NoRecentReviews(days) => write!(
+                f,
+                "no reviews in the last {} days, use a longer --accuracy-window",
+                days
+            ),
+            NoAssignments => write!(f, "no assignments in the cache"),
+            NoHistoryAfter(time) => write!(
+                f,
+                "the cache has no full day of reviews after {}",
+                time.format("%Y-%m-%d %H:%M")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for WksimError {}
+
+impl From<rusqlite::Error> for WksimError {
+    fn from(err: rusqlite::Error) -> Self {
+        WksimError::Database(err)
+    }
+}
+
+impl From<std::io::Error> for WksimError {
+    fn from(err: std::io::Error) -> Self {
+        WksimError::Io(err)
+    }
+}
+
+impl From<ureq::Error> for WksimError {
+    fn from(err: ureq::Error) -> Self {
+        WksimError::Api(Box::new(err))
+    }
+}
@@ -0,0 +1,82 @@
+use std::str::FromStr;
+
+use crate::{
+    model::{Subject, SubjectKind},
+    schedule::SessionSchedule,
+};
+
+/// Order in which available lessons are done, mirroring WaniKani's lesson
+/// settings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LessonOrder {
+    /// Lowest level first, then radicals, kanji and vocabulary
+    AscendingLevel,
+    Shuffled,
+    /// Subjects of the current level first, then ascending level
+    CurrentLevelFirst,
+    /// Radicals and kanji first, then vocabulary, each by ascending level
+    RadicalsKanjiFirst,
+}
+
+impl LessonOrder {
+    /// Sort key for lessons, lowest first. Not used for shuffled lessons.
+    pub fn sort_key(self, subject: &Subject, cur_level: u8) -> (bool, u8, SubjectKind, u16) {
+        let priority = match self {
+            Self::AscendingLevel | Self::Shuffled => false,
+            Self::CurrentLevelFirst => subject.level != cur_level,
+            Self::RadicalsKanjiFirst => subject.kind == SubjectKind::Vocabulary,
+        };
+
+        (priority, subject.level, subject.kind, subject.id.0)
+    }
+}
+
+impl FromStr for LessonOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ascending-level" => Ok(Self::AscendingLevel),
+            "shuffled" => Ok(Self::Shuffled),
+            "current-level-first" => Ok(Self::CurrentLevelFirst),
+            "radicals-kanji-first" => Ok(Self::RadicalsKanjiFirst),
+            _ => Err(format!("unknown lesson order {:?}", s)),
+        }
+    }
+}
+
+/// How the user paces their lessons.
+#[derive(Clone, Debug)]
+pub struct LessonPolicy {
+    /// Hours at which the user does lessons, after clearing their reviews
+    pub schedule: SessionSchedule,
+    pub max_per_day: Option<u32>,
+    pub max_per_session: Option<u32>,
+    /// Only start lessons while there are fewer subjects than this in the
+    /// Apprentice stages
+    pub max_apprentice: Option<u32>,
+    pub order: LessonOrder,
+}
+
+impl LessonPolicy {
+    /// Returns how many lessons the user is willing to start now, given the
+    /// number of lessons they already did today and in the current session,
+    /// and the number of subjects currently in the Apprentice stages.
+    pub fn lessons_allowed(
+        &self,
+        lessons_today: u32,
+        lessons_this_session: u32,
+        apprentice_count: u32,
+    ) -> u32 {
+        let today_limit = self
+            .max_per_day
+            .map_or(u32::MAX, |max| max.saturating_sub(lessons_today));
+        let session_limit = self
+            .max_per_session
+            .map_or(u32::MAX, |max| max.saturating_sub(lessons_this_session));
+        let apprentice_limit = self
+            .max_apprentice
+            .map_or(u32::MAX, |max| max.saturating_sub(apprentice_count));
+        today_limit.min(session_limit).min(apprentice_limit)
+    }
+}
@@ -1,146 +1,66 @@
+mod accuracy;
+mod backtest;
 mod database;
+mod error;
+mod lessons;
 mod model;
+mod report;
+mod schedule;
+mod sync;
 
-use chrono::{Duration, DurationRound};
+use chrono::{DateTime, Duration, Local, NaiveDate, NaiveDateTime, TimeZone, Timelike};
 use indicatif::ProgressBar;
 use model::{SubjectKind, MAX_LEVEL};
-use rand::{thread_rng, Rng};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 use std::{
     cmp::Reverse,
     collections::{BinaryHeap, HashMap},
     convert::{TryFrom, TryInto},
+    path::PathBuf,
+    sync::atomic::{AtomicU32, Ordering},
+    thread,
 };
 use structopt::StructOpt;
 
 use self::{
+    accuracy::{
+        AccuracyOverride, AccuracyPrior, Question, RecencyWeighting, ReviewResultProbability,
+    },
+    backtest::ActualHistory,
     database::DatabaseWrapper,
-    model::{Stage, Subject, SubjectID, NUM_STAGES},
+    error::{Result, RowRef, WksimError},
+    lessons::{LessonOrder, LessonPolicy},
+    model::{Assignment, Review, Srs, SrsID, Stage, Subject, SubjectID, NUM_STAGES},
+    report::{
+        BacktestReport, BacktestSummary, Grouping, LevelReport, OutputFormat, PeriodReport,
+        ReportKind, SimulationResults,
+    },
+    schedule::{Absence, DateRange, SessionSchedule},
 };
 
-#[derive(Clone, Debug)]
-struct StageProbabilityDistribution {
-    /// Stage probabilities should all be divided by this value
-    total: u32,
-    stage_probs: Vec<(Stage, u32)>,
-}
-
-impl StageProbabilityDistribution {
-    pub fn new(stage_probs: Vec<(Stage, u32)>) -> Self {
-        let total = stage_probs.iter().map(|(_stage, n)| n).sum();
-
-        Self { total, stage_probs }
-    }
-
-    pub fn is_empty(&self) -> bool {
-        self.total == 0
-    }
-
-    pub fn sample(&self) -> Option<Stage> {
-        let mut x = thread_rng().gen_range(0..self.total);
-
-        for (stage, stage_n) in &self.stage_probs {
-            if x < *stage_n {
-                return Some(*stage);
-            } else {
-                x -= stage_n;
-            }
-        }
-
-        None
-    }
-
-    /// Create a new distribution based on this one, but shifted up by `x`
-    pub fn shift(&self, x: isize) -> Self {
-        let mut new_dist = self.clone();
-        for (stage, _) in &mut new_dist.stage_probs {
-            let new_stage = isize::from(u8::from(*stage)) + x;
-            let new_stage = new_stage.clamp(0, isize::try_from(NUM_STAGES - 1).unwrap());
-            let new_stage = u8::try_from(new_stage).unwrap();
-            *stage = Stage::try_from(new_stage).unwrap();
-        }
-        new_dist
-    }
-}
-
-#[derive(Debug)]
-struct ReviewResultProbability {
-    by_prev_stage: [StageProbabilityDistribution; NUM_STAGES],
-}
-
-impl ReviewResultProbability {
-    pub fn new(db: &mut DatabaseWrapper) -> Self {
-        let mut stage_counts = [[0; NUM_STAGES]; NUM_STAGES];
-        for review in db.reviews() {
-            let review = review.unwrap();
-            stage_counts[review.start_stage as usize][review.end_stage as usize] += 1;
-        }
-
-        let mut by_prev_stage: [StageProbabilityDistribution; NUM_STAGES] = stage_counts
-            .iter()
-            .map(|end_stage_counts| {
-                StageProbabilityDistribution::new(
-                    end_stage_counts
-                        .iter()
-                        .copied()
-                        .enumerate()
-                        .filter_map(|(end_stage, count)| {
-                            if count > 0 {
-                                let end_stage = u8::try_from(end_stage).unwrap();
-                                let end_stage = Stage::try_from(end_stage).unwrap();
-                                Some((end_stage, count))
-                            } else {
-                                None
-                            }
-                        })
-                        .collect(),
-                )
-            })
-            .collect::<Vec<_>>()
-            .try_into()
-            .unwrap();
-
-        // Fill in unknown probabilities for higher stages (except "burned"),
-        // with those of the rows preceding them.
-        let last_non_empty_row = by_prev_stage
-            .iter()
-            .rposition(|row| !row.is_empty())
-            // TODO: don't panic here
-            .expect("No reviews at all?");
-        let (until_last, empty_rows) = by_prev_stage.split_at_mut(last_non_empty_row + 1);
-        let last_non_empty_row = until_last.last().unwrap();
-        let (_burned_row, empty_rows) = empty_rows.split_last_mut().unwrap();
-        for (i, row) in empty_rows.iter_mut().enumerate() {
-            // TODO: instead of shifting, store the "correct results"
-            // probabilities, then implement the stage distribution based on
-            // that + the formula from the wk knowledge guide
-            *row = last_non_empty_row.shift(isize::try_from(i + 1).unwrap());
-        }
-
-        Self { by_prev_stage }
-    }
-
-    pub fn sample_for(&self, prev_stage: Stage) -> Option<Stage> {
-        if prev_stage == Stage::Initiate {
-            // Initiate reviews always go to Apprentice1 once you answer them
-            // correctly.
-            return Some(Stage::Apprentice1);
-        }
-
-        self.by_prev_stage[prev_stage as usize].sample()
-    }
+fn load_subjects(db: &mut DatabaseWrapper) -> Result<HashMap<SubjectID, Subject>> {
+    db.subjects()?
+        .map(|subject| {
+            let subject = subject?;
+            Ok((subject.id, subject))
+        })
+        .collect()
 }
 
-fn load_subjects(db: &mut DatabaseWrapper) -> HashMap<SubjectID, Subject> {
-    db.subjects()
-        .map(|subject| {
-            let subject = subject.unwrap();
-            (subject.id, subject)
+fn load_srs_systems(db: &mut DatabaseWrapper) -> Result<HashMap<SrsID, Srs>> {
+    db.spaced_repetition_systems()?
+        .map(|srs| {
+            let srs = srs?;
+            Ok((srs.id, srs))
         })
         .collect()
 }
 
+/// Returns the subjects with the given level, sorted by ID so that simulations
+/// don't depend on `HashMap` iteration order.
 fn subjects_with_level(subjects: &HashMap<SubjectID, Subject>, level: u8) -> Vec<SubjectID> {
-    subjects
+    let mut subject_ids = subjects
         .iter()
         .filter_map(|(subject_id, subject)| {
             if subject.level == level {
@@ -149,22 +69,33 @@ fn subjects_with_level(subjects: &HashMap<SubjectID, Subject>, level: u8) -> Vec
                 None
             }
         })
-        .collect()
+        .collect::<Vec<_>>();
+    subject_ids.sort_unstable();
+    subject_ids
 }
 
 #[derive(Clone)]
 struct SubjectState {
     stage: Stage,
-    /// Simulation step number at which the subject can be reviewed again. None
-    /// means that it's burned!
+    /// SRS time (see `Simulator::srs_step`) at which the subject can be
+    /// reviewed again. None means that it's burned, or still waiting in the
+    /// lesson queue.
     next_review_time: Option<u32>,
+    /// SRS time of the last review or lesson, negative if it was before the
+    /// simulation started. None if not known.
+    last_review_time: Option<i64>,
+    /// Multiplies the odds of getting a review wrong. Determined at the first
+    /// review.
+    difficulty: Option<f64>,
 }
 
 impl SubjectState {
-    pub fn newly_unlocked(cur_time: u32) -> Self {
+    pub fn newly_unlocked(srs: &Srs) -> Self {
         Self {
-            stage: Stage::Initiate,
-            next_review_time: Some(cur_time),
+            stage: srs.unlocking_stage,
+            next_review_time: None,
+            last_review_time: None,
+            difficulty: None,
         }
     }
 }
@@ -173,67 +104,158 @@ impl SubjectState {
 struct Simulator<'a> {
     review_prob: &'a ReviewResultProbability,
     subjects: &'a HashMap<SubjectID, Subject>,
+    srs_systems: &'a HashMap<SrsID, Srs>,
+    schedule: &'a SessionSchedule,
+    lesson_policy: &'a LessonPolicy,
+    /// Time of simulation step 0. Each step is one hour.
+    base_time: DateTime<Local>,
     cur_step: u32,
+    /// Hours counted by the SRS timers since step 0. Same as `cur_step`,
+    /// except that it stands still while vacation mode is on.
+    srs_step: u32,
     subject_states: HashMap<SubjectID, SubjectState>,
     review_queue: BinaryHeap<(Reverse<u32>, SubjectID)>,
+    /// Unlocked subjects whose lessons haven't been started yet
+    lesson_queue: Vec<SubjectID>,
+    /// Day of the last lesson, and how many lessons were done on that day
+    lessons_day: Option<NaiveDate>,
+    lessons_today: u32,
+    /// Number of subjects in the Apprentice stages
+    apprentice_count: u32,
     cur_level: u8,
     cur_level_subjects: Vec<SubjectID>,
     cur_level_kanji: Vec<SubjectID>,
+    /// (level, step) pairs for each level-up during the simulation
+    level_ups: Vec<(u8, u32)>,
 }
 
 impl<'a> Simulator<'a> {
     pub fn new(
         review_prob: &'a ReviewResultProbability,
         subjects: &'a HashMap<SubjectID, Subject>,
-        db: &mut DatabaseWrapper,
-    ) -> Self {
-        let base_time = db
-            .next_review_time()
-            .expect("No available reviews")
-            // Round down to hour
-            .duration_trunc(Duration::days(1))
-            .unwrap();
+        srs_systems: &'a HashMap<SrsID, Srs>,
+        schedule: &'a SessionSchedule,
+        lesson_policy: &'a LessonPolicy,
+        start_time: DateTime<Local>,
+        assignments: impl IntoIterator<Item = Result<(RowRef, Assignment)>>,
+    ) -> Result<Self> {
+        for subject in subjects.values() {
+            if !srs_systems.contains_key(&subject.srs) {
+                return Err(WksimError::UnknownSrs {
+                    row: RowRef {
+                        table: "subjects",
+                        id: subject.id.0.into(),
+                    },
+                    srs: subject.srs,
+                });
+            }
+        }
 
-        let subject_states = db
-            .assignments()
+        // Round down to the start of the local hour, so that steps line up
+        // with the hours of the review schedule. This has to be done on the
+        // local time, as not all time zones are a whole number of hours
+        // away from UTC.
+        let naive_start = start_time.naive_local();
+        let base_time = Local
+            .from_local_datetime(&naive_start.date().and_hms(naive_start.hour(), 0, 0))
+            .earliest()
+            .unwrap_or(start_time);
+        let mut overdue_count = 0;
+
+        let subject_states = assignments
+            .into_iter()
             .map(|assignment| {
-                let assignment = assignment.unwrap();
+                let (row, assignment) = assignment?;
+
+                if !subjects.contains_key(&assignment.subject_id) {
+                    return Err(WksimError::UnknownSubject {
+                        row,
+                        subject_id: assignment.subject_id,
+                    });
+                }
 
                 let stage = assignment.stage;
+                let srs = &srs_systems[&subjects[&assignment.subject_id].srs];
+                let mut last_review_time = None;
 
                 let steps_from_base = if let Some(next_review_time) = assignment.next_review_time {
-                    assert_ne!(stage, Stage::Initiate);
+                    if stage == srs.unlocking_stage {
+                        return Err(WksimError::UnexpectedAvailableAt(row));
+                    }
                     let time_since = next_review_time.signed_duration_since(base_time);
-                    time_since.num_hours().max(0).try_into().unwrap()
+                    last_review_time = srs
+                        .hours_to_next_review(stage)
+                        .map(|interval| time_since.num_hours() - i64::from(interval));
+                    if time_since <= Duration::zero() {
+                        // Already available when the simulation starts, so
+                        // it's part of the backlog that's waiting for the
+                        // first review session.
+                        overdue_count += 1;
+                        Some(0)
+                    } else {
+                        // Reviews become available at the start of an hour
+                        Some(time_since.num_hours().try_into().unwrap())
+                    }
                 } else {
-                    assert_eq!(stage, Stage::Initiate);
-                    // Pretend we did the lesson, now subject is waiting for us
-                    // to review it immediately.
-                    0
+                    // Burned, or lesson not done yet, in which case the
+                    // subject goes in the lesson queue.
+                    None
                 };
 
-                (
+                Ok((
                     assignment.subject_id,
                     SubjectState {
                         stage,
-                        next_review_time: Some(steps_from_base),
+                        next_review_time: steps_from_base,
+                        last_review_time,
+                        difficulty: None,
                     },
-                )
+                ))
             })
-            .collect::<HashMap<_, _>>();
+            .collect::<Result<HashMap<_, _>>>()?;
+
+        if overdue_count > 0 {
+            eprintln!(
+                "{} reviews are already available at {}",
+                overdue_count,
+                base_time.format("%Y-%m-%d %H:%M")
+            );
+        }
 
         let review_queue = subject_states
             .iter()
             .filter_map(|(subject_id, state)| Some((Reverse(state.next_review_time?), *subject_id)))
             .collect();
 
+        let apprentice_count = subject_states
+            .iter()
+            .filter(|(subject_id, state)| {
+                srs_systems[&subjects[subject_id].srs].is_apprentice(state.stage)
+            })
+            .count()
+            .try_into()
+            .unwrap();
+
+        let mut lesson_queue = subject_states
+            .iter()
+            .filter_map(|(subject_id, state)| {
+                let srs = &srs_systems[&subjects[subject_id].srs];
+                if state.stage == srs.unlocking_stage {
+                    Some(*subject_id)
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        lesson_queue.sort_unstable();
+
         // The current level is the highest level for an unlocked subject, and
         // unlocked subjects are those included in subject_states.
         let cur_level = subject_states
             .keys()
             .map(|subject_id| subjects[subject_id].level)
             .max()
-            .expect("No unlocked subjects");
+            .ok_or(WksimError::NoAssignments)?;
 
         let cur_level_subjects = subjects_with_level(subjects, cur_level);
         let cur_level_kanji = cur_level_subjects
@@ -242,22 +264,32 @@ impl<'a> Simulator<'a> {
             .filter(|subject_id| subjects[subject_id].kind == SubjectKind::Kanji)
             .collect();
 
-        Self {
+        Ok(Self {
             review_prob,
             subjects,
+            srs_systems,
+            schedule,
+            lesson_policy,
+            base_time,
             cur_step: 0,
+            srs_step: 0,
             subject_states,
             review_queue,
+            lesson_queue,
+            lessons_day: None,
+            lessons_today: 0,
+            apprentice_count,
             cur_level,
             cur_level_subjects,
             cur_level_kanji,
-        }
+            level_ups: vec![],
+        })
     }
 
     fn peek_available_review(&self) -> Option<SubjectID> {
         let (Reverse(next_review_time), subject_id) = self.review_queue.peek()?;
 
-        if *next_review_time <= self.cur_step {
+        if *next_review_time <= self.srs_step {
             Some(*subject_id)
         } else {
             None
@@ -273,36 +305,109 @@ impl<'a> Simulator<'a> {
         }
     }
 
+    /// Returns the number of reviews that are available and waiting to be
+    /// done.
+    fn available_review_count(&self) -> u32 {
+        self.review_queue
+            .iter()
+            .filter(|(Reverse(next_review_time), _subject_id)| *next_review_time <= self.srs_step)
+            .count()
+            .try_into()
+            .unwrap()
+    }
+
+    fn cur_time(&self) -> DateTime<Local> {
+        self.base_time + Duration::hours(self.cur_step.into())
+    }
+
     /// Returns number of reviews performed in this step
-    fn step(&mut self) -> u32 {
+    fn step(&mut self, rng: &mut impl Rng) -> u32 {
+        let cur_time = self.cur_time();
+        let absence = self.schedule.absence_at(cur_time);
+        let lesson_time = self.lesson_policy.schedule.is_session_time(cur_time);
+        let review_time = self.schedule.is_session_time(cur_time);
+        let review_count = if absence.is_none() && (lesson_time || review_time) {
+            self.do_reviews(rng, lesson_time, review_time)
+        } else {
+            0
+        };
+
+        self.cur_step += 1;
+        if absence != Some(Absence::Vacation) {
+            self.srs_step += 1;
+        }
+
+        review_count
+    }
+
+    /// Performs all available reviews at the current step if `review_time` is
+    /// set, and starts lessons as allowed by the lesson policy if
+    /// `lesson_time` is set. Returns the number of reviews, including lesson
+    /// quizzes.
+    fn do_reviews(&mut self, rng: &mut impl Rng, lesson_time: bool, review_time: bool) -> u32 {
         let mut review_count = 0;
+        let mut lessons_this_session = 0;
 
         // Loop until done unlocking levels
-        while self.peek_available_review().is_some() {
+        loop {
+            if lesson_time {
+                let num_lessons = self.start_lessons(lessons_this_session, rng);
+                lessons_this_session += num_lessons;
+                review_count += num_lessons;
+            }
+
+            if !review_time || self.peek_available_review().is_none() {
+                break;
+            }
+
             // Loop over subjects up to current level
             while let Some(subject_id) = self.pop_available_review() {
                 let subject = &self.subjects[&subject_id];
+                let srs = &self.srs_systems[&subject.srs];
+                let review_prob = self.review_prob;
                 let subject_state = self.subject_states.get_mut(&subject_id).unwrap();
 
                 review_count += 1;
                 let old_stage = subject_state.stage;
-                let new_stage = self.review_prob.sample_for(old_stage).unwrap();
+                let difficulty = *subject_state
+                    .difficulty
+                    .get_or_insert_with(|| review_prob.sample_difficulty(subject, rng));
+                let lateness_factor = match (
+                    subject_state.last_review_time,
+                    srs.hours_to_next_review(old_stage),
+                ) {
+                    (Some(last_review_time), Some(interval)) if interval > 0 => {
+                        let elapsed_hours = i64::from(self.srs_step) - last_review_time;
+                        review_prob.lateness_factor(elapsed_hours as f64, interval.into())
+                    }
+                    _ => 1.0,
+                };
+                let incorrect_answers =
+                    review_prob.sample_for(subject, old_stage, difficulty * lateness_factor, rng);
+                let new_stage = srs.stage_after_review(old_stage, incorrect_answers.total());
 
                 subject_state.stage = new_stage;
-                if let Some(hours_to_next_review) = subject.srs.hours_to_next_review(new_stage) {
+                subject_state.last_review_time = Some(self.srs_step.into());
+                if srs.is_apprentice(old_stage) {
+                    self.apprentice_count -= 1;
+                }
+                if srs.is_apprentice(new_stage) {
+                    self.apprentice_count += 1;
+                }
+                if let Some(hours_to_next_review) = srs.hours_to_next_review(new_stage) {
                     // Reschedule
-                    let next_review_time = self.cur_step + hours_to_next_review;
+                    let next_review_time = self.srs_step + hours_to_next_review;
                     subject_state.next_review_time = Some(next_review_time);
                     self.review_queue
                         .push((Reverse(next_review_time), subject_id));
                 } else {
                     // Burned!
-                    debug_assert_eq!(new_stage, Stage::Burned);
+                    debug_assert_eq!(new_stage, srs.burning_stage);
                     subject_state.next_review_time = None;
                     // No need to reschedule in review_queue
                 }
 
-                if !old_stage.is_passing() && new_stage.is_passing() {
+                if !srs.is_passing(old_stage) && srs.is_passing(new_stage) {
                     // Check if we unlocked stuff
                     for subject2_id in &subject.depended_on_by {
                         let subject2_id = *subject2_id;
@@ -313,11 +418,7 @@ impl<'a> Simulator<'a> {
                         }
 
                         if self.may_unlock(subject2_id) {
-                            self.subject_states
-                                .insert(subject2_id, SubjectState::newly_unlocked(self.cur_step));
-                            // Prepare to do review immediately
-                            self.review_queue
-                                .push((Reverse(self.cur_step), subject2_id));
+                            self.unlock(subject2_id);
                         }
                     }
                 }
@@ -326,6 +427,7 @@ impl<'a> Simulator<'a> {
             // Check if done with current level
             if self.cur_level < MAX_LEVEL && self.passed_current_level() {
                 self.cur_level += 1;
+                self.level_ups.push((self.cur_level, self.cur_step));
                 self.cur_level_subjects = subjects_with_level(self.subjects, self.cur_level);
                 self.cur_level_kanji = self
                     .cur_level_subjects
@@ -335,31 +437,105 @@ impl<'a> Simulator<'a> {
                     .collect();
 
                 // Check if we unlocked stuff
-                for subject_id in &self.cur_level_subjects {
-                    let subject_id = *subject_id;
+                for i in 0..self.cur_level_subjects.len() {
+                    let subject_id = self.cur_level_subjects[i];
 
                     if self.may_unlock(subject_id) {
-                        self.subject_states
-                            .insert(subject_id, SubjectState::newly_unlocked(self.cur_step));
-
-                        // Prepare to do review immediately
-                        self.review_queue.push((Reverse(self.cur_step), subject_id));
+                        self.unlock(subject_id);
                     }
                 }
             }
         }
 
-        self.cur_step += 1;
-
         review_count
     }
 
+    fn unlock(&mut self, subject_id: SubjectID) {
+        let srs = &self.srs_systems[&self.subjects[&subject_id].srs];
+        self.subject_states
+            .insert(subject_id, SubjectState::newly_unlocked(srs));
+        self.lesson_queue.push(subject_id);
+    }
+
+    /// Starts as many lessons from the lesson queue as the lesson policy
+    /// allows, and returns their count. Each lesson's quiz is done right away,
+    /// which counts as a review and puts the subject in the starting stage.
+    fn start_lessons(&mut self, lessons_this_session: u32, rng: &mut impl Rng) -> u32 {
+        let today = self.cur_time().date().naive_local();
+        if self.lessons_day != Some(today) {
+            self.lessons_day = Some(today);
+            self.lessons_today = 0;
+        }
+
+        let mut num_lessons = 0;
+        while self.lesson_policy.lessons_allowed(
+            self.lessons_today,
+            lessons_this_session + num_lessons,
+            self.apprentice_count,
+        ) > 0
+        {
+            let subject_id = match self.pop_next_lesson(rng) {
+                Some(subject_id) => subject_id,
+                None => break,
+            };
+
+            // Lessons always go to the starting stage once you answer them
+            // correctly.
+            let srs = &self.srs_systems[&self.subjects[&subject_id].srs];
+            let srs_step = self.srs_step;
+            let subject_state = self.subject_states.get_mut(&subject_id).unwrap();
+            subject_state.stage = srs.starting_stage;
+            subject_state.last_review_time = Some(srs_step.into());
+            subject_state.next_review_time = srs
+                .hours_to_next_review(srs.starting_stage)
+                .map(|hours| srs_step + hours);
+            if let Some(next_review_time) = subject_state.next_review_time {
+                self.review_queue
+                    .push((Reverse(next_review_time), subject_id));
+            }
+            if srs.is_apprentice(srs.starting_stage) {
+                self.apprentice_count += 1;
+            }
+
+            self.lessons_today += 1;
+            num_lessons += 1;
+        }
+
+        num_lessons
+    }
+
+    /// Removes the next lesson to do from the lesson queue, according to the
+    /// lesson order.
+    fn pop_next_lesson(&mut self, rng: &mut impl Rng) -> Option<SubjectID> {
+        if self.lesson_queue.is_empty() {
+            return None;
+        }
+
+        let order = self.lesson_policy.order;
+        let index = if order == LessonOrder::Shuffled {
+            rng.gen_range(0..self.lesson_queue.len())
+        } else {
+            let (index, _subject_id) = self
+                .lesson_queue
+                .iter()
+                .enumerate()
+                .min_by_key(|(_index, subject_id)| {
+                    order.sort_key(&self.subjects[subject_id], self.cur_level)
+                })
+                .unwrap();
+            index
+        };
+
+        Some(self.lesson_queue.swap_remove(index))
+    }
+
     fn passed_current_level(&self) -> bool {
         let num_kanji = self.cur_level_kanji.len();
         let mut num_passed_kanji = 0;
         for subject_id in &self.cur_level_kanji {
             if let Some(subject_state) = self.subject_states.get(subject_id) {
-                if subject_state.stage.is_passing() {
+                let srs = &self.srs_systems[&self.subjects[subject_id].srs];
+                if srs.is_passing(subject_state.stage) {
                     num_passed_kanji += 1;
                 }
             }
@@ -389,134 +565,565 @@ impl<'a> Simulator<'a> {
     }
 }
 
-struct AverageByDay {
-    day_values: Vec<u64>,
-}
-
-impl AverageByDay {
-    pub fn new(num_days: usize) -> Self {
-        Self {
-            day_values: vec![0; num_days],
-        }
+fn parse_num_runs(s: &str) -> std::result::Result<u32, String> {
+    match s.parse::<u32>() {
+        Ok(num_runs) if num_runs > 0 => Ok(num_runs),
+        _ => Err(format!("{:?} is not a positive number of runs", s)),
     }
+}
 
-    pub fn add(&mut self, day: usize, value: u32) {
-        self.day_values[day] += u64::from(value);
+fn parse_percentile(s: &str) -> std::result::Result<u8, String> {
+    match s.parse::<u8>() {
+        Ok(p) if p <= 100 => Ok(p),
+        _ => Err(format!("{:?} is not a percentile between 0 and 100", s)),
     }
+}
 
-    pub fn average(&self, num_values: u32, day: usize) -> u32 {
-        (self.day_values[day] as f64 / f64::from(num_values)).round() as u32
+fn parse_prior_strength(s: &str) -> std::result::Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(strength) if strength >= 0.0 => Ok(strength),
+        _ => Err(format!("{:?} is not a non-negative number", s)),
     }
 }
 
-struct StageCountAverage {
-    stages: Vec<AverageByDay>,
+fn parse_days(s: &str) -> std::result::Result<f64, String> {
+    match s.parse::<f64>() {
+        Ok(days) if days > 0.0 && days.is_finite() => Ok(days),
+        _ => Err(format!("{:?} is not a positive number of days", s)),
+    }
 }
 
-impl StageCountAverage {
-    pub fn new(num_days: usize) -> Self {
-        let mut stages = Vec::with_capacity(NUM_STAGES);
-        stages.resize_with(NUM_STAGES, || AverageByDay::new(num_days));
-        Self { stages }
+/// Parses a local date, a local date and time, or an RFC 3339 timestamp.
+fn parse_start_time(s: &str) -> std::result::Result<DateTime<Local>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time.into());
     }
 
-    pub fn add_card_stage(&mut self, day: usize, stage: Stage) {
-        self.stages[stage as usize].add(day, 1);
-    }
+    let naive_time = NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M")
+        .or_else(|_| NaiveDateTime::parse_from_str(s, "%Y-%m-%dT%H:%M"))
+        .or_else(|_| NaiveDate::parse_from_str(s, "%Y-%m-%d").map(|date| date.and_hms(0, 0, 0)))
+        .map_err(|_| format!("{:?} is not a valid date or time", s))?;
 
-    pub fn average_for_stage_range(
-        &self,
-        num_values: u32,
-        day: usize,
-        start_stage: Stage,
-        end_stage: Stage,
-    ) -> u32 {
-        (start_stage as usize..=end_stage as usize)
-            .map(|stage| self.stages[stage].average(num_values, day))
-            .sum()
-    }
+    Local
+        .from_local_datetime(&naive_time)
+        .earliest()
+        .ok_or_else(|| format!("{:?} doesn't exist in the local time zone", s))
 }
 
 #[derive(StructOpt)]
 #[structopt(name = "wksim", about = "Wanikani review simulator")]
 struct Opt {
-    #[structopt(short, long, default_value = "100")]
+    /// Path of the cache database [default: wanikani_cache.db]
+    #[structopt(long, global = true, parse(from_os_str), conflicts_with = "profile")]
+    db: Option<PathBuf>,
+
+    /// Use a separate cache for this user, stored in the XDG data directory
+    #[structopt(short, long, global = true)]
+    profile: Option<String>,
+
+    #[structopt(short, long, default_value = "100", parse(try_from_str = parse_num_runs))]
     num_runs: u32,
 
     #[structopt(short = "d", long, default_value = "365")]
     num_days: usize,
+
+    /// Percentiles to show next to each mean
+    #[structopt(
+        long,
+        default_value = "10,50,90",
+        use_delimiter = true,
+        parse(try_from_str = parse_percentile)
+    )]
+    percentiles: Vec<u8>,
+
+    /// Number of simulations to run in parallel [default: number of CPUs]
+    #[structopt(short, long)]
+    jobs: Option<usize>,
+
+    /// Hours of the day at which you do your reviews, e.g. 8,13,21 [default:
+    /// every hour]
+    #[structopt(long, use_delimiter = true, parse(try_from_str = schedule::parse_hour))]
+    review_hours: Option<Vec<u32>>,
+
+    /// Hours at which you do your reviews on weekends [default: same as
+    /// --review-hours]
+    #[structopt(long, use_delimiter = true, parse(try_from_str = schedule::parse_hour))]
+    weekend_review_hours: Option<Vec<u32>>,
+
+    /// Hours of the day at which you do lessons [default: same as
+    /// --review-hours]
+    #[structopt(long, use_delimiter = true, parse(try_from_str = schedule::parse_hour))]
+    lesson_hours: Option<Vec<u32>>,
+
+    /// Maximum number of lessons per day [default: unlimited]
+    #[structopt(long)]
+    lessons_per_day: Option<u32>,
+
+    /// Maximum number of lessons per lesson session [default: unlimited]
+    #[structopt(long)]
+    lessons_per_session: Option<u32>,
+
+    /// Only do lessons while you have fewer than this many subjects in the
+    /// Apprentice stages [default: unlimited]
+    #[structopt(long)]
+    max_apprentice: Option<u32>,
+
+    /// Lesson order: ascending-level, shuffled, current-level-first or
+    /// radicals-kanji-first
+    #[structopt(long, default_value = "ascending-level")]
+    lesson_order: LessonOrder,
+
+    /// Only use reviews from the last N days to estimate your accuracy
+    #[structopt(long, parse(try_from_str = parse_days))]
+    accuracy_window: Option<f64>,
+
+    /// Give reviews half the weight every N days back when estimating your
+    /// accuracy, so that recent reviews count the most
+    #[structopt(long, parse(try_from_str = parse_days))]
+    accuracy_half_life: Option<f64>,
+
+    /// How many reviews' worth of weight to give to the prior that the
+    /// accuracy at each SRS stage is smoothed towards
+    #[structopt(long, default_value = "10", parse(try_from_str = parse_prior_strength))]
+    prior_strength: f64,
+
+    /// Smooth the accuracy towards that of a typical user, instead of your own
+    /// accuracy over all stages
+    #[structopt(long)]
+    population_prior: bool,
+
+    /// What if your accuracy was different? E.g. 0.9 to get 90% of reviews
+    /// fully right, or kanji=0.85 or guru1=0.95 for one subject type or SRS
+    /// stage. Overrides are applied in order.
+    #[structopt(long, use_delimiter = true, number_of_values = 1)]
+    accuracy: Vec<AccuracyOverride>,
+
+    /// What if you answered meanings correctly more often? E.g. 0.05 to add
+    /// 5% to the fraction of meanings you get right the first time.
+    #[structopt(long, allow_hyphen_values = true)]
+    meaning_accuracy_change: Option<f64>,
+
+    /// What if you answered readings correctly more often? E.g. 0.05 to add
+    /// 5% to the fraction of readings you get right the first time.
+    #[structopt(long, allow_hyphen_values = true)]
+    reading_accuracy_change: Option<f64>,
+
+    /// Dates on which you're in vacation mode, e.g. 2021-07-01..2021-07-14.
+    /// SRS timers are frozen, so nothing becomes available until you're back.
+    #[structopt(long, use_delimiter = true, number_of_values = 1)]
+    vacation: Vec<DateRange>,
+
+    /// Dates on which you don't do any reviews or lessons, e.g.
+    /// 2021-07-01..2021-07-14. Reviews keep piling up while you're away.
+    #[structopt(long = "break", use_delimiter = true, number_of_values = 1)]
+    breaks: Vec<DateRange>,
+
+    /// Time at which the simulation starts, e.g. "2021-06-01 08:00" or
+    /// "2021-06-01" [default: now]
+    #[structopt(long, parse(try_from_str = parse_start_time))]
+    start: Option<DateTime<Local>>,
+
+    /// Seed for the random number generator, to reproduce a previous
+    /// simulation. A random seed is used by default.
+    #[structopt(long)]
+    seed: Option<u64>,
+
+    /// What to report: days for per-day review and stage counts, or levels
+    /// for the time it takes to reach each level
+    #[structopt(long, default_value = "days")]
+    report: ReportKind,
+
+    /// Show results per day, week or month. Levels and stage counts are shown
+    /// as of the start of each period, and reviews are summed over it.
+    #[structopt(long, default_value = "day")]
+    group_by: Grouping,
+
+    /// Output format: table, csv or json
+    #[structopt(short, long, default_value = "table")]
+    format: OutputFormat,
+
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
+}
+
+#[derive(StructOpt)]
+enum Command {
+    /// Download new reviews, subjects and assignments from the WaniKani API
+    /// into the local cache
+    Sync {
+        #[structopt(long, env = "WANIKANI_API_KEY", hide_env_values = true)]
+        api_key: String,
+
+        #[structopt(long, default_value = sync::DEFAULT_API_URL)]
+        api_url: String,
+    },
+
+    /// Simulate from a past date using only the reviews done before it, and
+    /// compare the predictions with what actually happened
+    Backtest {
+        /// Time at which the backtest starts, e.g. "2021-01-01"
+        #[structopt(long, parse(try_from_str = parse_start_time))]
+        from: DateTime<Local>,
+    },
 }
 
 fn main() {
     let opt = Opt::from_args();
 
-    let db = database::open().unwrap();
-    let mut db = DatabaseWrapper::new(&db);
+    if let Err(err) = run(&opt) {
+        eprintln!("Error: {}", err);
+        std::process::exit(1);
+    }
+}
 
-    let review_prob = ReviewResultProbability::new(&mut db);
-    let subjects = load_subjects(&mut db);
+/// Runs a single simulation, adding its per-day values to `results`.
+fn simulate_run(mut sim: Simulator, mut rng: ChaCha8Rng, results: &mut SimulationResults) {
+    let num_days = results.levels.num_days();
+    let start_date = sim.base_time.date().naive_local();
+    for day in 0..num_days {
+        results.levels.add(day, sim.cur_level.into());
 
-    let sim = Simulator::new(&review_prob, &subjects, &mut db);
+        let mut stage_counts = [0; NUM_STAGES];
+        for subj_state in sim.subject_states.values() {
+            stage_counts[subj_state.stage as usize] += 1;
+        }
+        results.stage_counts.add_run(day, &stage_counts);
+        results.backlog.add(day, sim.available_review_count());
+
+        // Days follow the local calendar, so the first day is cut short if
+        // the simulation doesn't start at midnight, and DST changes make some
+        // days 23 or 25 hours long.
+        let date = start_date + Duration::days(day as i64);
+        let mut day_reviews = 0;
+        while sim.cur_time().date().naive_local() == date {
+            day_reviews += sim.step(&mut rng);
+        }
+        results.reviews.add(day, day_reviews);
+    }
 
-    let mut reviews = AverageByDay::new(opt.num_days);
-    let mut levels = AverageByDay::new(opt.num_days);
-    let mut deck_stage_counts = StageCountAverage::new(opt.num_days);
+    results.level_ups.add_run(&sim.level_ups);
+}
 
-    let pb = ProgressBar::new(opt.num_runs.into());
-    for _run in 0..opt.num_runs {
-        pb.inc(1);
+/// Returns the random number generator for the given simulation run. Each run
+/// gets its own stream, so results don't depend on the order in which runs are
+/// performed.
+fn run_rng(seed: u64, run: u32) -> ChaCha8Rng {
+    let mut rng = ChaCha8Rng::seed_from_u64(seed);
+    rng.set_stream(run.into());
+    rng
+}
 
-        let mut sim = sim.clone();
+impl Opt {
+    fn review_schedule(&self) -> SessionSchedule {
+        let every_hour = (0..24).collect::<Vec<_>>();
+        let weekday_hours = self.review_hours.as_ref().unwrap_or(&every_hour);
+        let weekend_hours = self.weekend_review_hours.as_ref().unwrap_or(weekday_hours);
+        SessionSchedule::new(weekday_hours, weekend_hours)
+            .with_absences(&self.vacation, &self.breaks)
+    }
 
-        for day in 0..opt.num_days {
-            levels.add(day, sim.cur_level.into());
-            for subj_state in sim.subject_states.values() {
-                deck_stage_counts.add_card_stage(day, subj_state.stage);
-            }
+    fn lesson_policy(&self) -> LessonPolicy {
+        let schedule = match &self.lesson_hours {
+            Some(hours) => SessionSchedule::new(hours, hours),
+            None => self.review_schedule(),
+        };
+
+        LessonPolicy {
+            schedule,
+            max_per_day: self.lessons_per_day,
+            max_per_session: self.lessons_per_session,
+            max_apprentice: self.max_apprentice,
+            order: self.lesson_order,
+        }
+    }
+
+    /// Returns how reviews are weighted when estimating accuracy at `now`.
+    fn recency_weighting(&self, now: DateTime<Local>) -> RecencyWeighting {
+        RecencyWeighting {
+            now,
+            window_days: self.accuracy_window,
+            half_life_days: self.accuracy_half_life,
+        }
+    }
+
+    fn accuracy_prior(&self) -> AccuracyPrior {
+        AccuracyPrior {
+            strength: self.prior_strength,
+            population: self.population_prior,
+        }
+    }
 
-            let day_reviews = (0..24).map(|_| sim.step()).sum::<u32>();
-            reviews.add(day, day_reviews);
+    fn db_path(&self) -> Result<PathBuf> {
+        match (&self.db, &self.profile) {
+            (Some(path), _) => Ok(path.clone()),
+            (None, Some(profile)) => database::profile_path(profile),
+            (None, None) => Ok(PathBuf::from(database::DEFAULT_DB_PATH)),
         }
     }
+}
+
+/// Estimates the user's accuracy as of `now` from their reviews, then applies
+/// the accuracy changes given on the command line.
+fn load_review_prob(
+    opt: &Opt,
+    reviews: impl Iterator<Item = Result<Review>>,
+    subjects: &HashMap<SubjectID, Subject>,
+    srs_systems: &HashMap<SrsID, Srs>,
+    assignments: &[(RowRef, Assignment)],
+    now: DateTime<Local>,
+) -> Result<ReviewResultProbability> {
+    let mut review_prob = ReviewResultProbability::new(
+        reviews,
+        subjects,
+        srs_systems,
+        assignments,
+        &opt.recency_weighting(now),
+        &opt.accuracy_prior(),
+    )?;
+
+    for accuracy_override in &opt.accuracy {
+        review_prob.override_accuracy(accuracy_override);
+    }
+    if let Some(change) = opt.meaning_accuracy_change {
+        review_prob.adjust_accuracy(Question::Meaning, change);
+    }
+    if let Some(change) = opt.reading_accuracy_change {
+        review_prob.adjust_accuracy(Question::Reading, change);
+    }
+
+    Ok(review_prob)
+}
+
+/// Runs `opt.num_runs` simulations of `num_days` days each, in parallel.
+fn simulate_runs(opt: &Opt, sim: &Simulator, num_days: usize) -> SimulationResults {
+    let seed = opt.seed.unwrap_or_else(|| rand::thread_rng().gen());
+    eprintln!("Using seed {}", seed);
+
+    let pb = ProgressBar::new(opt.num_runs.into());
+    let next_run = AtomicU32::new(0);
+
+    let num_threads = opt
+        .jobs
+        .unwrap_or_else(|| thread::available_parallelism().map_or(1, |n| n.get()))
+        .max(1);
+
+    let results = thread::scope(|scope| {
+        let workers = (0..num_threads)
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = SimulationResults::new(num_days);
+
+                    loop {
+                        let run = next_run.fetch_add(1, Ordering::Relaxed);
+                        if run >= opt.num_runs {
+                            break;
+                        }
+
+                        simulate_run(sim.clone(), run_rng(seed, run), &mut results);
+                        pb.inc(1);
+                    }
+
+                    results
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let mut results = SimulationResults::new(num_days);
+        for worker in workers {
+            results.merge(worker.join().unwrap());
+        }
+        results
+    });
 
     pb.finish_with_message("done");
 
-    for day in 0..opt.num_days {
-        println!(
-            "Day {:>3}: level {:>2}, {:>4} reviews  A: {:>4}  G: {:>4}  M: {:>4}  E: {:>4}  B: {:>4}",
-            day,
-            levels.average(opt.num_runs, day),
-            reviews.average(opt.num_runs, day),
-            deck_stage_counts.average_for_stage_range(
-                opt.num_runs,
-                day,
-                Stage::Apprentice1,
-                Stage::Apprentice4
-            ),
-            deck_stage_counts.average_for_stage_range(
-                opt.num_runs,
-                day,
-                Stage::Guru1,
-                Stage::Guru2
-            ),
-            deck_stage_counts.average_for_stage_range(
-                opt.num_runs,
-                day,
-                Stage::Master,
-                Stage::Master
-            ),
-            deck_stage_counts.average_for_stage_range(
-                opt.num_runs,
-                day,
-                Stage::Enlightened,
-                Stage::Enlightened
+    results
+}
+
+/// Simulates from `from` using only the reviews done before it, and compares
+/// the results with the cached history.
+fn backtest(opt: &Opt, db: &mut DatabaseWrapper, from: DateTime<Local>) -> Result<()> {
+    let subjects = load_subjects(db)?;
+    let srs_systems = load_srs_systems(db)?;
+    let assignments = db.assignments()?.collect::<Result<Vec<_>>>()?;
+    let review_prob = load_review_prob(
+        opt,
+        db.reviews()?.filter(|review| {
+            review
+                .as_ref()
+                .map_or(true, |review| review.created_at < from)
+        }),
+        &subjects,
+        &srs_systems,
+        &assignments,
+        from,
+    )?;
+    let reviews = db.reviews()?.collect::<Result<Vec<_>>>()?;
+
+    let past_assignments =
+        backtest::assignments_at(from, &assignments, &reviews, &subjects, &srs_systems)?;
+
+    let schedule = opt.review_schedule();
+    let lesson_policy = opt.lesson_policy();
+    let sim = Simulator::new(
+        &review_prob,
+        &subjects,
+        &srs_systems,
+        &schedule,
+        &lesson_policy,
+        from,
+        past_assignments.into_iter().map(Ok),
+    )?;
+
+    // Only compare days that are fully covered by the cached reviews, i.e.
+    // those before the day of the last review.
+    let last_review_time = reviews
+        .iter()
+        .map(|review| review.created_at)
+        .max()
+        .ok_or(WksimError::NoReviews)?;
+    let start_date = sim.base_time.date().naive_local();
+    let days_of_history = (last_review_time.date().naive_local() - start_date).num_days();
+    let num_days = usize::try_from(days_of_history)
+        .unwrap_or(0)
+        .min(opt.num_days);
+    if num_days == 0 {
+        return Err(WksimError::NoHistoryAfter(sim.base_time));
+    }
+
+    let actual = ActualHistory::new(sim.base_time, num_days, &assignments, &reviews, &subjects);
+    let results = simulate_runs(opt, &sim, num_days);
+
+    let periods = opt
+        .group_by
+        .periods(start_date, num_days)
+        .into_iter()
+        .map(|days| {
+            BacktestReport::new(
+                opt.group_by,
+                days,
+                start_date,
+                &results,
+                &actual,
+                &opt.percentiles,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    if let Some(summary) = BacktestSummary::new(&periods) {
+        eprintln!(
+            "Backtest over {} days: reviews off by {:.1} per period on average",
+            num_days, summary.reviews_error
+        );
+        if let Some(in_band) = summary.reviews_in_band {
+            eprintln!(
+                "Actual reviews within the predicted p{}-p{} range in {:.0}% of periods",
+                opt.percentiles.iter().min().unwrap(),
+                opt.percentiles.iter().max().unwrap(),
+                in_band * 100.0
+            );
+        }
+        eprintln!(
+            "Level at the start of the last period: {} predicted, {} actual",
+            summary.final_level, summary.actual_final_level
+        );
+    }
+
+    let stdout = std::io::stdout();
+    report::write_backtest_report(&mut stdout.lock(), opt.format, &periods)?;
+
+    Ok(())
+}
+
+fn run(opt: &Opt) -> Result<()> {
+    let db_path = opt.db_path()?;
+    let db = database::open(&db_path)?;
+
+    if let Some(Command::Sync { api_key, api_url }) = &opt.cmd {
+        let client = sync::Client::new(api_url, api_key);
+        return sync::update_cache(&db, &client);
+    }
+
+    let mut db = DatabaseWrapper::new(&db)?;
+
+    if let Some(Command::Backtest { from }) = opt.cmd {
+        return backtest(opt, &mut db, from);
+    }
+
+    let subjects = load_subjects(&mut db)?;
+    let srs_systems = load_srs_systems(&mut db)?;
+    let start_time = opt.start.unwrap_or_else(Local::now);
+    let assignments = db.assignments()?.collect::<Result<Vec<_>>>()?;
+    let review_prob = load_review_prob(
+        opt,
+        db.reviews()?,
+        &subjects,
+        &srs_systems,
+        &assignments,
+        start_time,
+    )?;
+
+    let schedule = opt.review_schedule();
+    let lesson_policy = opt.lesson_policy();
+    let sim = Simulator::new(
+        &review_prob,
+        &subjects,
+        &srs_systems,
+        &schedule,
+        &lesson_policy,
+        start_time,
+        assignments.into_iter().map(Ok),
+    )?;
+
+    let results = simulate_runs(opt, &sim, opt.num_days);
+
+    match results.mean_days_per_level() {
+        Some(days) => eprintln!("Average pace: {:.1} days per level", days),
+        None => eprintln!("Average pace: no level-ups"),
+    }
+
+    let level_reports = LevelReport::all(&results.level_ups, sim.base_time);
+    if let Some(max_level) = level_reports.iter().find(|level| level.level == MAX_LEVEL) {
+        match max_level.median_date {
+            Some(date) => eprintln!(
+                "Median date of reaching level {}: {}",
+                MAX_LEVEL,
+                date.format("%Y-%m-%d")
             ),
-            deck_stage_counts.average_for_stage_range(
-                opt.num_runs,
-                day,
-                Stage::Burned,
-                Stage::Burned
+            None => eprintln!(
+                "Level {} reached in only {:.0}% of runs",
+                MAX_LEVEL,
+                max_level.reached * 100.0
             ),
-        );
+        }
     }
+
+    let stdout = std::io::stdout();
+    match opt.report {
+        ReportKind::Days => {
+            let start_date = sim.base_time.date().naive_local();
+            let period_reports = opt
+                .group_by
+                .periods(start_date, opt.num_days)
+                .into_iter()
+                .map(|days| {
+                    PeriodReport::new(
+                        opt.group_by,
+                        days,
+                        start_date,
+                        &results,
+                        &schedule,
+                        &opt.percentiles,
+                    )
+                })
+                .collect::<Vec<_>>();
+            report::write_period_report(&mut stdout.lock(), opt.format, &period_reports)?;
+        }
+
+        ReportKind::Levels => {
+            report::write_level_report(&mut stdout.lock(), opt.format, &level_reports)?;
+        }
+    }
+
+    Ok(())
 }
@@ -5,38 +5,54 @@ use num_enum::{IntoPrimitive, TryFromPrimitive};
 
 pub const MAX_LEVEL: u8 = 60;
 
-#[derive(Clone, Copy, Debug, PartialEq, Eq, TryFromPrimitive)]
-#[repr(u8)]
-pub enum Srs {
-    Normal = 1,
-    Accelerated,
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct SrsID(pub u16);
+
+/// A spaced repetition system, as defined by the WaniKani API.
+#[derive(Clone, Debug)]
+pub struct Srs {
+    pub id: SrsID,
+    /// Hours until the next review, indexed by stage position. None for
+    /// stages that aren't reviewed, i.e. the unlocking and burning stages.
+    pub intervals: Vec<Option<u32>>,
+    pub unlocking_stage: Stage,
+    pub starting_stage: Stage,
+    pub passing_stage: Stage,
+    pub burning_stage: Stage,
 }
 
 impl Srs {
-    pub fn hours_to_next_review(self, stage: Stage) -> Option<u32> {
-        use Srs::*;
-        use Stage::*;
-
-        Some(match (self, stage) {
-            (_, Initiate) | (_, Burned) => {
-                return None;
-            }
-
-            (Normal, Apprentice1) => 4,
-            (Normal, Apprentice2) => 8,
-            (Normal, Apprentice3) => 23,
-            (Normal, Apprentice4) => 47,
-
-            (Accelerated, Apprentice1) => 2,
-            (Accelerated, Apprentice2) => 4,
-            (Accelerated, Apprentice3) => 8,
-            (Accelerated, Apprentice4) => 23,
-
-            (_, Guru1) => 167,
-            (_, Guru2) => 335,
-            (_, Master) => 719,
-            (_, Enlightened) => 2879,
-        })
+    pub fn hours_to_next_review(&self, stage: Stage) -> Option<u32> {
+        *self.intervals.get(stage as usize)?
+    }
+
+    pub fn is_passing(&self, stage: Stage) -> bool {
+        stage >= self.passing_stage
+    }
+
+    /// Returns the stage after a review with the given number of incorrect
+    /// answers, using the formula from the WaniKani Knowledge Guide: every
+    /// two incorrect answers (rounded up) drop the subject by one stage, or
+    /// by two stages once it's passed.
+    pub fn stage_after_review(&self, stage: Stage, incorrect_answers: u32) -> Stage {
+        let stage_position = u32::from(u8::from(stage));
+        let new_position = if incorrect_answers == 0 {
+            (stage_position + 1).min(u8::from(self.burning_stage).into())
+        } else {
+            let adjustment = incorrect_answers.div_ceil(2);
+            let penalty_factor = if self.is_passing(stage) { 2 } else { 1 };
+            stage_position
+                .saturating_sub(adjustment * penalty_factor)
+                .max(u8::from(self.starting_stage).into())
+        };
+
+        Stage::try_from(u8::try_from(new_position).unwrap()).unwrap()
+    }
+
+    /// Returns whether the stage is one of the Apprentice stages, i.e. started
+    /// but not passed yet.
+    pub fn is_apprentice(&self, stage: Stage) -> bool {
+        stage >= self.starting_stage && !self.is_passing(stage)
     }
 }
 
@@ -55,19 +71,29 @@ pub enum Stage {
     Burned = 9,
 }
 
-impl Stage {
-    pub fn is_passing(self) -> bool {
-        self >= Stage::Guru1
-    }
+pub const NUM_STAGES: usize = 10;
+
+/// Number of incorrect answers given for a subject during one review.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct IncorrectAnswers {
+    pub meaning: u32,
+    pub reading: u32,
 }
 
-pub const NUM_STAGES: usize = 10;
+impl IncorrectAnswers {
+    pub fn total(self) -> u32 {
+        self.meaning + self.reading
+    }
+}
 
 #[derive(Debug)]
 pub struct Review {
-    pub srs: Srs,
+    pub subject_id: SubjectID,
+    pub srs: SrsID,
+    pub created_at: DateTime<Local>,
     pub start_stage: Stage,
     pub end_stage: Stage,
+    pub incorrect_answers: IncorrectAnswers,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
@@ -80,6 +106,14 @@ pub enum SubjectKind {
     Vocabulary,
 }
 
+pub const NUM_SUBJECT_KINDS: usize = 3;
+
+pub const SUBJECT_KINDS: [SubjectKind; NUM_SUBJECT_KINDS] = [
+    SubjectKind::Radical,
+    SubjectKind::Kanji,
+    SubjectKind::Vocabulary,
+];
+
 impl TryFrom<&str> for SubjectKind {
     type Error = ();
 
@@ -100,12 +134,14 @@ pub struct Subject {
     pub kind: SubjectKind,
     pub depends_on: Vec<SubjectID>,
     pub depended_on_by: Vec<SubjectID>,
-    pub srs: Srs,
+    pub srs: SrsID,
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Assignment {
     pub subject_id: SubjectID,
     pub stage: Stage,
     pub next_review_time: Option<DateTime<Local>>,
+    pub unlocked_at: Option<DateTime<Local>>,
+    pub started_at: Option<DateTime<Local>>,
 }
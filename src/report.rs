@@ -0,0 +1,847 @@
+use std::{convert::TryFrom, io::Write, ops::Range, str::FromStr};
+
+use chrono::{DateTime, Datelike, Duration, Local, NaiveDate};
+use serde_json::json;
+
+use crate::{
+    backtest::ActualHistory,
+    model::{Stage, MAX_LEVEL, NUM_STAGES},
+    schedule::{Absence, SessionSchedule},
+};
+
+/// Per-day values of some quantity, with one value for each simulation run.
+pub struct ValuesByDay {
+    day_values: Vec<Vec<u32>>,
+}
+
+impl ValuesByDay {
+    pub fn new(num_days: usize) -> Self {
+        Self {
+            day_values: vec![vec![]; num_days],
+        }
+    }
+
+    pub fn num_days(&self) -> usize {
+        self.day_values.len()
+    }
+
+    pub fn add(&mut self, day: usize, value: u32) {
+        self.day_values[day].push(value);
+    }
+
+    pub fn values(&self, day: usize) -> &[u32] {
+        &self.day_values[day]
+    }
+
+    /// Returns the sum of each run's values over the given days.
+    pub fn sum_values(&self, days: Range<usize>) -> Vec<u32> {
+        let mut sums = self.values(days.start).to_vec();
+        for day in days.start + 1..days.end {
+            for (sum, value) in sums.iter_mut().zip(self.values(day)) {
+                *sum += value;
+            }
+        }
+        sums
+    }
+
+    pub fn merge(&mut self, other: ValuesByDay) {
+        for (values, other_values) in self.day_values.iter_mut().zip(other.day_values) {
+            values.extend(other_values);
+        }
+    }
+}
+
+/// A group of stages shown together in the report.
+pub struct StageBucket {
+    /// Short label used in the table output
+    pub label: &'static str,
+    /// Name used in the CSV and JSON output
+    pub name: &'static str,
+    pub start_stage: Stage,
+    pub end_stage: Stage,
+}
+
+pub const STAGE_BUCKETS: [StageBucket; 5] = [
+    StageBucket {
+        label: "A",
+        name: "apprentice",
+        start_stage: Stage::Apprentice1,
+        end_stage: Stage::Apprentice4,
+    },
+    StageBucket {
+        label: "G",
+        name: "guru",
+        start_stage: Stage::Guru1,
+        end_stage: Stage::Guru2,
+    },
+    StageBucket {
+        label: "M",
+        name: "master",
+        start_stage: Stage::Master,
+        end_stage: Stage::Master,
+    },
+    StageBucket {
+        label: "E",
+        name: "enlightened",
+        start_stage: Stage::Enlightened,
+        end_stage: Stage::Enlightened,
+    },
+    StageBucket {
+        label: "B",
+        name: "burned",
+        start_stage: Stage::Burned,
+        end_stage: Stage::Burned,
+    },
+];
+
+pub struct StageCountsByDay {
+    stages: Vec<ValuesByDay>,
+}
+
+impl StageCountsByDay {
+    pub fn new(num_days: usize) -> Self {
+        let mut stages = Vec::with_capacity(NUM_STAGES);
+        stages.resize_with(NUM_STAGES, || ValuesByDay::new(num_days));
+        Self { stages }
+    }
+
+    /// Adds the number of subjects in each stage at the given day, for a
+    /// single run.
+    pub fn add_run(&mut self, day: usize, stage_counts: &[u32; NUM_STAGES]) {
+        for (stage, count) in stage_counts.iter().enumerate() {
+            self.stages[stage].add(day, *count);
+        }
+    }
+
+    pub fn merge(&mut self, other: StageCountsByDay) {
+        for (stage, other_stage) in self.stages.iter_mut().zip(other.stages) {
+            stage.merge(other_stage);
+        }
+    }
+
+    /// Returns the number of subjects in the given stage range, for each run.
+    pub fn values_for_stage_range(
+        &self,
+        day: usize,
+        start_stage: Stage,
+        end_stage: Stage,
+    ) -> Vec<u32> {
+        let mut values = self.stages[start_stage as usize].values(day).to_vec();
+        for stage in start_stage as usize + 1..=end_stage as usize {
+            for (value, stage_value) in values.iter_mut().zip(self.stages[stage].values(day)) {
+                *value += stage_value;
+            }
+        }
+        values
+    }
+}
+
+/// Simulation steps at which each level was reached, across all runs.
+pub struct LevelUps {
+    /// Indexed by level, with one value for each run that reached the level
+    level_steps: Vec<Vec<u32>>,
+    num_runs: u32,
+}
+
+impl LevelUps {
+    pub fn new() -> Self {
+        Self {
+            level_steps: vec![vec![]; usize::from(MAX_LEVEL) + 1],
+            num_runs: 0,
+        }
+    }
+
+    /// Adds the (level, step) pairs of a single run's level-ups.
+    pub fn add_run(&mut self, level_ups: &[(u8, u32)]) {
+        for (level, step) in level_ups {
+            self.level_steps[usize::from(*level)].push(*step);
+        }
+        self.num_runs += 1;
+    }
+
+    pub fn merge(&mut self, other: LevelUps) {
+        for (steps, other_steps) in self.level_steps.iter_mut().zip(other.level_steps) {
+            steps.extend(other_steps);
+        }
+        self.num_runs += other.num_runs;
+    }
+}
+
+/// Per-day results of a set of simulation runs.
+pub struct SimulationResults {
+    pub levels: ValuesByDay,
+    pub reviews: ValuesByDay,
+    /// Number of available reviews waiting at the start of each day
+    pub backlog: ValuesByDay,
+    pub stage_counts: StageCountsByDay,
+    pub level_ups: LevelUps,
+}
+
+impl SimulationResults {
+    pub fn new(num_days: usize) -> Self {
+        Self {
+            levels: ValuesByDay::new(num_days),
+            reviews: ValuesByDay::new(num_days),
+            backlog: ValuesByDay::new(num_days),
+            stage_counts: StageCountsByDay::new(num_days),
+            level_ups: LevelUps::new(),
+        }
+    }
+
+    /// Returns the average number of days it took to gain a level, or None if
+    /// no levels were gained.
+    pub fn mean_days_per_level(&self) -> Option<f64> {
+        let num_days = self.levels.num_days();
+        if num_days < 2 {
+            return None;
+        }
+
+        let first_levels = self.levels.values(0);
+        let last_levels = self.levels.values(num_days - 1);
+        let levels_gained = last_levels
+            .iter()
+            .zip(first_levels)
+            .map(|(last, first)| f64::from(last - first))
+            .sum::<f64>();
+        if levels_gained == 0.0 {
+            return None;
+        }
+
+        let mean_levels_gained = levels_gained / first_levels.len() as f64;
+        Some((num_days - 1) as f64 / mean_levels_gained)
+    }
+
+    /// Adds the results of another set of runs.
+    pub fn merge(&mut self, other: SimulationResults) {
+        self.levels.merge(other.levels);
+        self.reviews.merge(other.reviews);
+        self.backlog.merge(other.backlog);
+        self.stage_counts.merge(other.stage_counts);
+        self.level_ups.merge(other.level_ups);
+    }
+}
+
+fn mean(values: &[u32]) -> u32 {
+    let sum = values.iter().copied().map(u64::from).sum::<u64>();
+    (sum as f64 / values.len() as f64).round() as u32
+}
+
+/// Nearest-rank percentile of already sorted values.
+fn percentile(sorted_values: &[u32], percentile: u8) -> u32 {
+    let rank = (f64::from(percentile) / 100.0 * (sorted_values.len() - 1) as f64).round();
+    sorted_values[rank as usize]
+}
+
+/// Summary of a quantity's values across all runs.
+pub struct Stats {
+    pub mean: u32,
+    /// (percentile, value) pairs
+    pub percentiles: Vec<(u8, u32)>,
+}
+
+impl Stats {
+    pub fn new(values: &[u32], percentiles: &[u8]) -> Self {
+        let mut sorted_values = values.to_vec();
+        sorted_values.sort_unstable();
+
+        Self {
+            mean: mean(values),
+            percentiles: percentiles
+                .iter()
+                .map(|p| (*p, percentile(&sorted_values, *p)))
+                .collect(),
+        }
+    }
+
+    /// Formats the mean, followed by the percentiles.
+    fn format(&self, width: usize) -> String {
+        let mut s = format!("{:>width$}", self.mean, width = width);
+
+        if !self.percentiles.is_empty() {
+            let bands = self
+                .percentiles
+                .iter()
+                .map(|(_p, value)| format!("{:>width$}", value, width = width))
+                .collect::<Vec<_>>();
+            s += &format!(" ({})", bands.join("/"));
+        }
+
+        s
+    }
+
+    fn csv_header(&self, name: &str) -> Vec<String> {
+        let mut columns = vec![format!("{}_mean", name)];
+        columns.extend(
+            self.percentiles
+                .iter()
+                .map(|(p, _value)| format!("{}_p{}", name, p)),
+        );
+        columns
+    }
+
+    fn csv_values(&self) -> Vec<String> {
+        let mut values = vec![self.mean.to_string()];
+        values.extend(self.percentiles.iter().map(|(_p, value)| value.to_string()));
+        values
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        let mut json = serde_json::Map::new();
+        json.insert("mean".to_string(), self.mean.into());
+        for (p, value) in &self.percentiles {
+            json.insert(format!("p{}", p), (*value).into());
+        }
+        json.into()
+    }
+}
+
+/// Length of the periods shown in the report.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Grouping {
+    Day,
+    Week,
+    Month,
+}
+
+impl Grouping {
+    /// Splits the simulated days into periods. The first and last periods
+    /// may be partial.
+    pub fn periods(self, start_date: NaiveDate, num_days: usize) -> Vec<Range<usize>> {
+        let period_of = |day: usize| {
+            let date = start_date + Duration::days(day as i64);
+            match self {
+                Self::Day => (date.year(), date.ordinal()),
+                Self::Week => (date.iso_week().year(), date.iso_week().week()),
+                Self::Month => (date.year(), date.month()),
+            }
+        };
+
+        let mut periods: Vec<Range<usize>> = vec![];
+        for day in 0..num_days {
+            match periods.last_mut() {
+                Some(period) if period_of(period.start) == period_of(day) => {
+                    period.end = day + 1;
+                }
+                _ => periods.push(day..day + 1),
+            }
+        }
+        periods
+    }
+}
+
+impl FromStr for Grouping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "day" => Ok(Self::Day),
+            "week" => Ok(Self::Week),
+            "month" => Ok(Self::Month),
+            _ => Err(format!("unknown grouping {:?}", s)),
+        }
+    }
+}
+
+/// Results for a single day, week or month. Levels, backlog and stage counts
+/// are those at the start of the period, and reviews are summed over the whole
+/// period.
+pub struct PeriodReport {
+    pub grouping: Grouping,
+    /// Index of the period's first simulated day
+    pub day: usize,
+    pub date: NaiveDate,
+    pub num_days: usize,
+    /// Set if the user is away on any day of the period
+    pub absence: Option<Absence>,
+    pub level: Stats,
+    pub reviews: Stats,
+    /// Available reviews waiting to be done
+    pub backlog: Stats,
+    /// Subject counts, for each of `STAGE_BUCKETS`
+    pub stages: Vec<Stats>,
+}
+
+impl PeriodReport {
+    pub fn new(
+        grouping: Grouping,
+        days: Range<usize>,
+        start_date: NaiveDate,
+        results: &SimulationResults,
+        schedule: &SessionSchedule,
+        percentiles: &[u8],
+    ) -> Self {
+        let day = days.start;
+        let absence = days
+            .clone()
+            .find_map(|day| schedule.absence_on(start_date + Duration::days(day as i64)));
+
+        Self {
+            grouping,
+            day,
+            date: start_date + Duration::days(day as i64),
+            num_days: days.len(),
+            absence,
+            level: Stats::new(results.levels.values(day), percentiles),
+            reviews: Stats::new(&results.reviews.sum_values(days), percentiles),
+            backlog: Stats::new(results.backlog.values(day), percentiles),
+            stages: STAGE_BUCKETS
+                .iter()
+                .map(|bucket| {
+                    let values = results.stage_counts.values_for_stage_range(
+                        day,
+                        bucket.start_stage,
+                        bucket.end_stage,
+                    );
+                    Stats::new(&values, percentiles)
+                })
+                .collect(),
+        }
+    }
+
+    fn label(&self) -> String {
+        period_label(self.grouping, self.date)
+    }
+}
+
+fn period_label(grouping: Grouping, date: NaiveDate) -> String {
+    match grouping {
+        Grouping::Day => date.format("%a %Y-%m-%d").to_string(),
+        Grouping::Week => date.format("Week of %Y-%m-%d").to_string(),
+        Grouping::Month => date.format("%Y-%m").to_string(),
+    }
+}
+
+/// Predicted and actual results for a single day, week or month of a
+/// backtest.
+pub struct BacktestReport {
+    pub grouping: Grouping,
+    /// Index of the period's first simulated day
+    pub day: usize,
+    pub date: NaiveDate,
+    pub num_days: usize,
+    pub level: Stats,
+    pub actual_level: u32,
+    pub reviews: Stats,
+    pub actual_reviews: u32,
+}
+
+impl BacktestReport {
+    pub fn new(
+        grouping: Grouping,
+        days: Range<usize>,
+        start_date: NaiveDate,
+        results: &SimulationResults,
+        actual: &ActualHistory,
+        percentiles: &[u8],
+    ) -> Self {
+        let day = days.start;
+
+        Self {
+            grouping,
+            day,
+            date: start_date + Duration::days(day as i64),
+            num_days: days.len(),
+            level: Stats::new(results.levels.values(day), percentiles),
+            actual_level: actual.levels[day],
+            reviews: Stats::new(&results.reviews.sum_values(days.clone()), percentiles),
+            actual_reviews: actual.reviews[days].iter().sum(),
+        }
+    }
+
+    fn label(&self) -> String {
+        period_label(self.grouping, self.date)
+    }
+}
+
+/// How well a backtest's predictions matched what actually happened.
+pub struct BacktestSummary {
+    /// Mean absolute difference between the mean predicted and the actual
+    /// review counts, per period
+    pub reviews_error: f64,
+    /// Fraction of periods where the actual review count was between the
+    /// lowest and highest predicted percentiles, if at least two percentiles
+    /// were requested
+    pub reviews_in_band: Option<f64>,
+    pub final_level: u32,
+    pub actual_final_level: u32,
+}
+
+impl BacktestSummary {
+    /// Returns None if there are no periods.
+    pub fn new(periods: &[BacktestReport]) -> Option<Self> {
+        let last_period = periods.last()?;
+
+        let reviews_error = periods
+            .iter()
+            .map(|period| (f64::from(period.reviews.mean) - f64::from(period.actual_reviews)).abs())
+            .sum::<f64>()
+            / periods.len() as f64;
+
+        let in_band = |period: &BacktestReport| {
+            let low = period.reviews.percentiles.iter().map(|(_p, v)| *v).min()?;
+            let high = period.reviews.percentiles.iter().map(|(_p, v)| *v).max()?;
+            Some((low..=high).contains(&period.actual_reviews))
+        };
+        let reviews_in_band = if last_period.reviews.percentiles.len() >= 2 {
+            let num_in_band = periods
+                .iter()
+                .filter(|period| in_band(period) == Some(true))
+                .count();
+            Some(num_in_band as f64 / periods.len() as f64)
+        } else {
+            None
+        };
+
+        Some(Self {
+            reviews_error,
+            reviews_in_band,
+            final_level: last_period.level.mean,
+            actual_final_level: last_period.actual_level,
+        })
+    }
+}
+
+/// Distribution of the time it took to reach a level, across all runs. Times
+/// are None if not enough runs reached the level during the simulation.
+pub struct LevelReport {
+    pub level: u8,
+    /// Fraction of runs that reached the level
+    pub reached: f64,
+    pub min_days: Option<f64>,
+    pub median_days: Option<f64>,
+    pub p90_days: Option<f64>,
+    pub median_date: Option<DateTime<Local>>,
+}
+
+impl LevelReport {
+    /// Returns reports for all levels that were reached in any run.
+    /// `base_time` is the time of simulation step 0.
+    pub fn all(level_ups: &LevelUps, base_time: DateTime<Local>) -> Vec<Self> {
+        level_ups
+            .level_steps
+            .iter()
+            .enumerate()
+            .filter(|(_level, steps)| !steps.is_empty())
+            .map(|(level, steps)| {
+                let mut steps = steps.clone();
+                steps.sort_unstable();
+
+                // Runs that didn't reach the level count as taking forever
+                let step_percentile = |p: u8| {
+                    let rank = (f64::from(p) / 100.0 * f64::from(level_ups.num_runs - 1)).round();
+                    steps.get(rank as usize).copied()
+                };
+                let to_days = |step: u32| f64::from(step) / 24.0;
+
+                let median_step = step_percentile(50);
+
+                Self {
+                    level: u8::try_from(level).unwrap(),
+                    reached: steps.len() as f64 / f64::from(level_ups.num_runs),
+                    min_days: Some(to_days(steps[0])),
+                    median_days: median_step.map(to_days),
+                    p90_days: step_percentile(90).map(to_days),
+                    median_date: median_step.map(|step| base_time + Duration::hours(step.into())),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Which results to report.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportKind {
+    /// Per-day level, review and stage counts
+    Days,
+    /// Time to reach each level
+    Levels,
+}
+
+impl FromStr for ReportKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "days" => Ok(Self::Days),
+            "levels" => Ok(Self::Levels),
+            _ => Err(format!("unknown report {:?}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Table,
+    Csv,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(Self::Table),
+            "csv" => Ok(Self::Csv),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown output format {:?}", s)),
+        }
+    }
+}
+
+pub fn write_period_report(
+    w: &mut impl Write,
+    format: OutputFormat,
+    periods: &[PeriodReport],
+) -> std::io::Result<()> {
+    match format {
+        OutputFormat::Table => write_period_table(w, periods),
+        OutputFormat::Csv => write_period_csv(w, periods),
+        OutputFormat::Json => write_period_json(w, periods),
+    }
+}
+
+fn write_period_table(w: &mut impl Write, periods: &[PeriodReport]) -> std::io::Result<()> {
+    for period in periods {
+        write!(
+            w,
+            "{}: level {}, {} reviews, {} waiting",
+            period.label(),
+            period.level.format(2),
+            period.reviews.format(4),
+            period.backlog.format(4),
+        )?;
+
+        for (bucket, stats) in STAGE_BUCKETS.iter().zip(&period.stages) {
+            write!(w, "  {}: {}", bucket.label, stats.format(4))?;
+        }
+
+        if let Some(absence) = period.absence {
+            write!(w, "  ({})", absence.name())?;
+        }
+
+        writeln!(w)?;
+    }
+
+    Ok(())
+}
+
+fn write_period_csv(w: &mut impl Write, periods: &[PeriodReport]) -> std::io::Result<()> {
+    let first_period = match periods.first() {
+        Some(period) => period,
+        None => return Ok(()),
+    };
+
+    let mut header = vec![
+        "day".to_string(),
+        "date".to_string(),
+        "days".to_string(),
+        "away".to_string(),
+    ];
+    header.extend(first_period.level.csv_header("level"));
+    header.extend(first_period.reviews.csv_header("reviews"));
+    header.extend(first_period.backlog.csv_header("backlog"));
+    for (bucket, stats) in STAGE_BUCKETS.iter().zip(&first_period.stages) {
+        header.extend(stats.csv_header(bucket.name));
+    }
+    writeln!(w, "{}", header.join(","))?;
+
+    for period in periods {
+        let mut row = vec![
+            period.day.to_string(),
+            period.date.format("%Y-%m-%d").to_string(),
+            period.num_days.to_string(),
+            period.absence.map_or("", Absence::name).to_string(),
+        ];
+        row.extend(period.level.csv_values());
+        row.extend(period.reviews.csv_values());
+        row.extend(period.backlog.csv_values());
+        for stats in &period.stages {
+            row.extend(stats.csv_values());
+        }
+        writeln!(w, "{}", row.join(","))?;
+    }
+
+    Ok(())
+}
+
+fn write_period_json(w: &mut impl Write, periods: &[PeriodReport]) -> std::io::Result<()> {
+    let periods = periods
+        .iter()
+        .map(|period| {
+            let stages = STAGE_BUCKETS
+                .iter()
+                .zip(&period.stages)
+                .map(|(bucket, stats)| (bucket.name.to_string(), stats.to_json()))
+                .collect::<serde_json::Map<_, _>>();
+
+            json!({
+                "day": period.day,
+                "date": period.date.format("%Y-%m-%d").to_string(),
+                "days": period.num_days,
+                "away": period.absence.map(Absence::name),
+                "level": period.level.to_json(),
+                "reviews": period.reviews.to_json(),
+                "backlog": period.backlog.to_json(),
+                "stages": stages,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    serde_json::to_writer_pretty(&mut *w, &periods)?;
+    writeln!(w)
+}
+
+pub fn write_level_report(
+    w: &mut impl Write,
+    format: OutputFormat,
+    levels: &[LevelReport],
+) -> std::io::Result<()> {
+    match format {
+        OutputFormat::Table => write_level_table(w, levels),
+        OutputFormat::Csv => write_level_csv(w, levels),
+        OutputFormat::Json => write_level_json(w, levels),
+    }
+}
+
+fn format_date(date: DateTime<Local>) -> String {
+    date.format("%Y-%m-%d").to_string()
+}
+
+fn write_level_table(w: &mut impl Write, levels: &[LevelReport]) -> std::io::Result<()> {
+    let format_days = |days: Option<f64>| match days {
+        Some(days) => format!("{:>5.1}", days),
+        None => format!("{:>5}", "-"),
+    };
+
+    for level in levels {
+        writeln!(
+            w,
+            "Level {:>2}: reached in {:>3.0}% of runs, after {} days (min {}, p90 {}), median date {}",
+            level.level,
+            level.reached * 100.0,
+            format_days(level.median_days),
+            format_days(level.min_days),
+            format_days(level.p90_days),
+            level.median_date.map_or("-".to_string(), format_date),
+        )?;
+    }
+
+    Ok(())
+}
+
+fn write_level_csv(w: &mut impl Write, levels: &[LevelReport]) -> std::io::Result<()> {
+    let format_days = |days: Option<f64>| days.map_or(String::new(), |days| format!("{:.2}", days));
+
+    writeln!(w, "level,reached,min_days,median_days,p90_days,median_date")?;
+    for level in levels {
+        writeln!(
+            w,
+            "{},{:.3},{},{},{},{}",
+            level.level,
+            level.reached,
+            format_days(level.min_days),
+            format_days(level.median_days),
+            format_days(level.p90_days),
+            level.median_date.map_or(String::new(), format_date),
+        )?;
+    }
+
+    Ok(())
+}
+
+fn write_level_json(w: &mut impl Write, levels: &[LevelReport]) -> std::io::Result<()> {
+    let levels = levels
+        .iter()
+        .map(|level| {
+            json!({
+                "level": level.level,
+                "reached": level.reached,
+                "min_days": level.min_days,
+                "median_days": level.median_days,
+                "p90_days": level.p90_days,
+                "median_date": level.median_date.map(format_date),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    serde_json::to_writer_pretty(&mut *w, &levels)?;
+    writeln!(w)
+}
+
+pub fn write_backtest_report(
+    w: &mut impl Write,
+    format: OutputFormat,
+    periods: &[BacktestReport],
+) -> std::io::Result<()> {
+    match format {
+        OutputFormat::Table => write_backtest_table(w, periods),
+        OutputFormat::Csv => write_backtest_csv(w, periods),
+        OutputFormat::Json => write_backtest_json(w, periods),
+    }
+}
+
+fn write_backtest_table(w: &mut impl Write, periods: &[BacktestReport]) -> std::io::Result<()> {
+    for period in periods {
+        writeln!(
+            w,
+            "{}: level {} (actual {:>2}), {} reviews (actual {:>4})",
+            period.label(),
+            period.level.format(2),
+            period.actual_level,
+            period.reviews.format(4),
+            period.actual_reviews,
+        )?;
+    }
+
+    Ok(())
+}
+
+fn write_backtest_csv(w: &mut impl Write, periods: &[BacktestReport]) -> std::io::Result<()> {
+    let first_period = match periods.first() {
+        Some(period) => period,
+        None => return Ok(()),
+    };
+
+    let mut header = vec!["day".to_string(), "date".to_string(), "days".to_string()];
+    header.extend(first_period.level.csv_header("level"));
+    header.push("actual_level".to_string());
+    header.extend(first_period.reviews.csv_header("reviews"));
+    header.push("actual_reviews".to_string());
+    writeln!(w, "{}", header.join(","))?;
+
+    for period in periods {
+        let mut row = vec![
+            period.day.to_string(),
+            period.date.format("%Y-%m-%d").to_string(),
+            period.num_days.to_string(),
+        ];
+        row.extend(period.level.csv_values());
+        row.push(period.actual_level.to_string());
+        row.extend(period.reviews.csv_values());
+        row.push(period.actual_reviews.to_string());
+        writeln!(w, "{}", row.join(","))?;
+    }
+
+    Ok(())
+}
+
+fn write_backtest_json(w: &mut impl Write, periods: &[BacktestReport]) -> std::io::Result<()> {
+    let periods = periods
+        .iter()
+        .map(|period| {
+            json!({
+                "day": period.day,
+                "date": period.date.format("%Y-%m-%d").to_string(),
+                "days": period.num_days,
+                "level": period.level.to_json(),
+                "actual_level": period.actual_level,
+                "reviews": period.reviews.to_json(),
+                "actual_reviews": period.actual_reviews,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    serde_json::to_writer_pretty(&mut *w, &periods)?;
+    writeln!(w)
+}
@@ -0,0 +1,129 @@
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, Local, NaiveDate, Timelike, Weekday};
+
+/// Why the user isn't doing any reviews or lessons on a given day.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Absence {
+    /// Vacation mode is on, so SRS timers are frozen and no new reviews
+    /// become available
+    Vacation,
+    /// Reviews keep becoming available and pile up
+    Break,
+}
+
+impl Absence {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Vacation => "vacation",
+            Self::Break => "break",
+        }
+    }
+}
+
+/// Range of local dates, both ends included.
+#[derive(Clone, Copy, Debug)]
+pub struct DateRange {
+    start: NaiveDate,
+    end: NaiveDate,
+}
+
+impl DateRange {
+    pub fn contains(&self, date: NaiveDate) -> bool {
+        (self.start..=self.end).contains(&date)
+    }
+}
+
+/// Parses "START..END", or a single date for a one-day range.
+impl FromStr for DateRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse_date = |date: &str| {
+            NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .map_err(|_| format!("{:?} is not a valid date", date))
+        };
+
+        let (start, end) = match s.split_once("..") {
+            Some((start, end)) => (parse_date(start)?, parse_date(end)?),
+            None => (parse_date(s)?, parse_date(s)?),
+        };
+        if end < start {
+            return Err(format!("{:?} ends before it starts", s));
+        }
+
+        Ok(Self { start, end })
+    }
+}
+
+/// Hours of the day at which the user sits down to do their reviews or lessons,
+/// and days on which they don't.
+#[derive(Clone, Debug)]
+pub struct SessionSchedule {
+    weekday_hours: [bool; 24],
+    weekend_hours: [bool; 24],
+    vacations: Vec<DateRange>,
+    breaks: Vec<DateRange>,
+}
+
+impl SessionSchedule {
+    pub fn new(weekday_hours: &[u32], weekend_hours: &[u32]) -> Self {
+        Self {
+            weekday_hours: Self::hour_mask(weekday_hours),
+            weekend_hours: Self::hour_mask(weekend_hours),
+            vacations: vec![],
+            breaks: vec![],
+        }
+    }
+
+    /// Adds days spent in vacation mode, and days without any sessions.
+    pub fn with_absences(mut self, vacations: &[DateRange], breaks: &[DateRange]) -> Self {
+        self.vacations.extend_from_slice(vacations);
+        self.breaks.extend_from_slice(breaks);
+        self
+    }
+
+    fn hour_mask(hours: &[u32]) -> [bool; 24] {
+        let mut mask = [false; 24];
+        for hour in hours {
+            mask[*hour as usize] = true;
+        }
+        mask
+    }
+
+    /// Returns whether the user does a session during the hour
+    /// starting at `time`.
+    pub fn is_session_time(&self, time: DateTime<Local>) -> bool {
+        let hours = match time.weekday() {
+            Weekday::Sat | Weekday::Sun => &self.weekend_hours,
+            _ => &self.weekday_hours,
+        };
+
+        hours[time.hour() as usize]
+    }
+
+    /// Returns why the user is away on `date`, if they are. Vacation mode wins
+    /// if a day is in both a vacation and a break.
+    pub fn absence_on(&self, date: NaiveDate) -> Option<Absence> {
+        if self.vacations.iter().any(|range| range.contains(date)) {
+            Some(Absence::Vacation)
+        } else if self.breaks.iter().any(|range| range.contains(date)) {
+            Some(Absence::Break)
+        } else {
+            None
+        }
+    }
+
+    /// Returns why the user is away during the hour starting at `time`, if
+    /// they are.
+    pub fn absence_at(&self, time: DateTime<Local>) -> Option<Absence> {
+        self.absence_on(time.date().naive_local())
+    }
+}
+
+pub fn parse_hour(s: &str) -> Result<u32, String> {
+    match s.parse::<u32>() {
+        Ok(hour) if hour < 24 => Ok(hour),
+        _ => Err(format!("{:?} is not an hour between 0 and 23", s)),
+    }
+}
@@ -0,0 +1,288 @@
+use std::{
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use indicatif::ProgressBar;
+use rusqlite::Connection;
+
+use crate::{
+    database,
+    error::{Result, WksimError},
+};
+
+pub const DEFAULT_API_URL: &str = "https://api.wanikani.com/v2";
+
+/// API revision that the cached JSON format corresponds to.
+const API_REVISION: &str = "20170710";
+
+/// How many times to retry a request that hit the rate limit.
+const MAX_RATE_LIMIT_RETRIES: u32 = 5;
+
+/// How long to wait after hitting the rate limit, if the response doesn't say
+/// when it resets. WaniKani's limit is per minute.
+const DEFAULT_RATE_LIMIT_WAIT: Duration = Duration::from_secs(60);
+
+pub struct Client {
+    api_url: String,
+    api_key: String,
+}
+
+impl Client {
+    pub fn new(api_url: &str, api_key: &str) -> Self {
+        Self {
+            api_url: api_url.trim_end_matches('/').to_string(),
+            api_key: api_key.to_string(),
+        }
+    }
+
+    fn get(&self, url: &str) -> ureq::Request {
+        ureq::get(url)
+            .set("Authorization", &format!("Bearer {}", self.api_key))
+            .set("Wanikani-Revision", API_REVISION)
+    }
+
+    /// Sends the request, waiting and retrying while the rate limit is hit.
+    fn call(&self, request: ureq::Request) -> Result<ureq::Response> {
+        let mut retries = 0;
+        loop {
+            match request.clone().call() {
+                Err(ureq::Error::Status(429, response)) if retries < MAX_RATE_LIMIT_RETRIES => {
+                    thread::sleep(rate_limit_wait(&response));
+                    retries += 1;
+                }
+                result => return Ok(result?),
+            }
+        }
+    }
+
+    /// Fetches a single collection page. Returns the objects in it, the total
+    /// number of objects in the collection, and the URL of the next page, if
+    /// any.
+    fn fetch_page(
+        &self,
+        request: ureq::Request,
+    ) -> Result<(Vec<serde_json::Value>, u64, Option<String>)> {
+        let mut page: serde_json::Value = self
+            .call(request)?
+            .into_json()
+            .map_err(|err| WksimError::InvalidApiResponse(err.to_string()))?;
+
+        let total_count = page["total_count"].as_u64().unwrap_or(0);
+        let next_url = page["pages"]["next_url"].as_str().map(str::to_string);
+        let objects = match page["data"].take() {
+            serde_json::Value::Array(objects) => objects,
+            _ => {
+                return Err(WksimError::InvalidApiResponse(
+                    "collection has no data array".to_string(),
+                ));
+            }
+        };
+
+        Ok((objects, total_count, next_url))
+    }
+
+    /// Downloads all objects of the given type that were updated after the
+    /// latest one already in the cache, and stores them.
+    ///
+    /// Collections are paged by ID rather than by update time, so the objects
+    /// are only committed once all pages have been fetched. Otherwise a failed
+    /// sync could leave a cache whose latest update time is newer than that
+    /// of objects on the missing pages, and the next sync would skip them.
+    pub fn update_table(&self, conn: &Connection, obj_type: &str) -> Result<()> {
+        let last_obj_time = database::last_object_time(conn, obj_type)?;
+
+        let mut request = self.get(&format!("{}/{}", self.api_url, obj_type));
+        if let Some(last_obj_time) = &last_obj_time {
+            request = request.query("updated_after", last_obj_time);
+        }
+
+        println!("Updating {}", obj_type);
+
+        let tx = conn.unchecked_transaction()?;
+
+        let (objects, total_count, mut next_url) = self.fetch_page(request)?;
+        let pb = ProgressBar::new(total_count);
+        database::insert_objects(&tx, obj_type, &objects)?;
+        pb.inc(objects.len() as u64);
+
+        while let Some(url) = next_url {
+            let (objects, _total_count, url) = self.fetch_page(self.get(&url))?;
+            database::insert_objects(&tx, obj_type, &objects)?;
+            pb.inc(objects.len() as u64);
+            next_url = url;
+        }
+
+        tx.commit()?;
+        pb.finish();
+
+        Ok(())
+    }
+}
+
+/// Returns how long to wait before retrying a rate-limited request, from the
+/// `RateLimit-Reset` header, which holds a Unix timestamp.
+fn rate_limit_wait(response: &ureq::Response) -> Duration {
+    let reset = match response
+        .header("RateLimit-Reset")
+        .and_then(|reset| reset.parse::<u64>().ok())
+    {
+        Some(reset) => reset,
+        None => return DEFAULT_RATE_LIMIT_WAIT,
+    };
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    // The reset time has a resolution of one second, so wait an extra second
+    // to make sure it has passed.
+    Duration::from_secs(reset.saturating_sub(now) + 1).min(DEFAULT_RATE_LIMIT_WAIT)
+}
+
+pub fn update_cache(conn: &Connection, client: &Client) -> Result<()> {
+    database::create_tables(conn)?;
+
+    for obj_type in &database::OBJECT_TYPES {
+        client.update_table(conn, obj_type)?;
+    }
+
+    println!("Done!");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        sync::mpsc,
+    };
+
+    use super::*;
+
+    /// A response served by `MockServer`: status code, extra headers and body.
+    type MockResponse = (u16, Vec<(&'static str, String)>, String);
+
+    /// Minimal HTTP server that serves the given responses in order, one per
+    /// connection, and reports the request line of each request it gets.
+    struct MockServer {
+        base_url: String,
+        requests: mpsc::Receiver<String>,
+    }
+
+    impl MockServer {
+        fn start(responses: impl FnOnce(&str) -> Vec<MockResponse>) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let base_url = format!("http://{}", listener.local_addr().unwrap());
+            let responses = responses(&base_url);
+            let (sender, requests) = mpsc::channel();
+
+            thread::spawn(move || {
+                for (status, headers, body) in responses {
+                    let (stream, _addr) = listener.accept().unwrap();
+                    let mut reader = BufReader::new(stream);
+
+                    let mut request_line = String::new();
+                    reader.read_line(&mut request_line).unwrap();
+                    loop {
+                        let mut line = String::new();
+                        reader.read_line(&mut line).unwrap();
+                        if line.trim_end().is_empty() {
+                            break;
+                        }
+                    }
+                    sender.send(request_line.trim_end().to_string()).unwrap();
+
+                    let mut stream = reader.into_inner();
+                    write!(
+                        stream,
+                        "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\n\
+                         Content-Length: {}\r\nConnection: close\r\n",
+                        status,
+                        body.len()
+                    )
+                    .unwrap();
+                    for (name, value) in headers {
+                        write!(stream, "{}: {}\r\n", name, value).unwrap();
+                    }
+                    write!(stream, "\r\n{}", body).unwrap();
+                }
+            });
+
+            Self { base_url, requests }
+        }
+
+        fn next_request(&self) -> String {
+            self.requests.recv_timeout(Duration::from_secs(10)).unwrap()
+        }
+    }
+
+    fn recorded_page(name: &str, base_url: &str) -> String {
+        let page = match name {
+            "reviews_page1" => include_str!("../testdata/sync/reviews_page1.json"),
+            "reviews_page2" => include_str!("../testdata/sync/reviews_page2.json"),
+            "reviews_empty" => include_str!("../testdata/sync/reviews_empty.json"),
+            _ => unreachable!(),
+        };
+        page.replace("{base_url}", base_url)
+    }
+
+    #[test]
+    fn update_table_follows_pages_and_resumes() {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        let server = MockServer::start(|base_url| {
+            vec![
+                (
+                    429,
+                    vec![("RateLimit-Reset", now.to_string())],
+                    "{}".to_string(),
+                ),
+                (200, vec![], recorded_page("reviews_page1", base_url)),
+                (200, vec![], recorded_page("reviews_page2", base_url)),
+                (200, vec![], recorded_page("reviews_empty", base_url)),
+            ]
+        });
+        let client = Client::new(&server.base_url, "test-key");
+        let conn = Connection::open_in_memory().unwrap();
+        database::create_tables(&conn).unwrap();
+
+        client.update_table(&conn, "reviews").unwrap();
+        assert_eq!(server.next_request(), "GET /reviews HTTP/1.1");
+        assert_eq!(server.next_request(), "GET /reviews HTTP/1.1");
+        assert_eq!(
+            server.next_request(),
+            "GET /reviews?page_after_id=2 HTTP/1.1"
+        );
+
+        let rows = conn
+            .prepare("SELECT id, object FROM reviews ORDER BY id")
+            .unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?)))
+            .unwrap()
+            .collect::<rusqlite::Result<Vec<(i64, String)>>>()
+            .unwrap();
+        assert_eq!(
+            rows,
+            [
+                (1, "review".to_string()),
+                (2, "review".to_string()),
+                (3, "review".to_string()),
+            ]
+        );
+
+        // The second sync only asks for objects updated since the newest
+        // cached one, even though it was on the first page.
+        client.update_table(&conn, "reviews").unwrap();
+        assert_eq!(
+            server.next_request(),
+            "GET /reviews?updated_after=2021-06-01T10%3A00%3A00.000000Z HTTP/1.1"
+        );
+    }
+}